@@ -1,5 +1,6 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::hash::Hash;
+use std::time::Duration;
 
 use serde::de::{self, Visitor};
 use serde::ser::SerializeSeq;
@@ -9,6 +10,11 @@ use serde_yaml::Value;
 
 use crate::schema::{Schema, SchemaList};
 
+/// extensions of unfinished downloads
+pub const TEMP_EXTENSIONS: [&str; 3] = ["part", "crdownload", "!qB"];
+/// seconds
+pub const DEFAULT_SETTLE: f64 = 1.;
+
 /// A struct to hold comma serated string or vec<string> values
 #[derive(Debug, Default, Clone)]
 pub struct CommaSeperated(pub Vec<String>);
@@ -79,7 +85,7 @@ pub enum ConfigDatatype {
 ///         children: OtherSchemaName
 ///         filename: "{id}-{name}.yaml"
 /// ```
-#[derive(Debug, Serialize, Default)]
+#[derive(Debug, Serialize, Default, Clone)]
 pub struct SchemaConfig {
     pub items: HashMap<String, SchemaConfigItem>,
 }
@@ -111,6 +117,60 @@ impl<'de> Visitor<'de> for SchemaConfigVisitor {
     }
 }
 
+/// What to do when the destination is already taken
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ConflictPolicy {
+    /// leave the file where it is
+    #[default]
+    Skip,
+    /// add a number at the end of the name
+    Rename,
+    /// replace the file at the destination
+    Overwrite,
+    /// remove the file if it's identical to the destination, skip otherwise
+    Dedupe,
+}
+
+/// How `schema.field` find its schema
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Notation {
+    /// one is the start of the other, `anim.name` for `anime`
+    #[default]
+    Prefix,
+    /// only the full name
+    Strict,
+    /// a prefix, or a typo away, `anmie.name` for `anime`
+    Fuzzy,
+}
+
+/// How a file is placed at the destination
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Action {
+    #[default]
+    Move,
+    Copy,
+    Hardlink,
+    /// symlink with absolute path
+    Symlink,
+    /// symlink with path relative to the destination folder
+    RelativeSymlink,
+}
+
+impl Action {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Action::Move => "move",
+            Action::Copy => "copy",
+            Action::Hardlink => "hardlink",
+            Action::Symlink => "symlink",
+            Action::RelativeSymlink => "relative-symlink",
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Default, Clone)]
 pub struct SchemaConfigItem {
     #[serde(default)]
@@ -119,6 +179,12 @@ pub struct SchemaConfigItem {
     pub children: CommaSeperated,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub filename: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub conflict: Option<ConflictPolicy>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub action: Option<Action>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub notation: Option<Notation>,
 }
 
 impl SchemaConfigItem {
@@ -131,6 +197,9 @@ impl SchemaConfigItem {
             fields: CommaSeperated(fields),
             children: CommaSeperated(children),
             filename: self.filename.clone(),
+            conflict: self.conflict,
+            action: self.action,
+            notation: self.notation,
         }
     }
 }
@@ -142,7 +211,7 @@ impl SchemaConfigItem {
 ///    - "{?}.{?w}": pattern1, pattern2
 ///    - ["{?}.{?w}","pat1,pat2"]
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct ImportConfig {
     pub list: Vec<(String, CommaSeperated)>,
 }
@@ -245,6 +314,8 @@ impl<'se> Serialize for ImportConfig {
 /// _meta:
 ///     schema: "schema"
 ///     ignore_schema: true
+///     temp_extensions: part, crdownload, !qB
+///     settle: 2
 ///     ... schema config
 #[derive(Debug, Serialize, Deserialize, Default, Clone)]
 pub struct MetaConfig {
@@ -252,10 +323,39 @@ pub struct MetaConfig {
     pub schema: Option<String>,
     #[serde(default)]
     pub ignore_schema: bool,
+    /// files with these extensions are still being downloaded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temp_extensions: Option<CommaSeperated>,
+    /// seconds a file should stay the same before it's organized
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub settle: Option<f64>,
     #[serde(flatten)]
     pub other: SchemaConfigItem,
 }
 
+/// choices made for one file (in organize --interactive), saved next to it as `<file>.meta.yaml`
+/// ``` yaml
+/// ignore: true
+/// destination: anime/naruto/01.mp4
+/// schema: anime
+/// fields:
+///     name: naruto
+#[derive(Debug, Serialize, Deserialize, Default, Clone, PartialEq)]
+pub struct FileMeta {
+    /// never organize this file
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub ignore: bool,
+    /// used instead of the path from schema, relative to the library
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub destination: Option<String>,
+    /// the file always go through this schema
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schema: Option<String>,
+    /// used instead of the value captured by the pattern
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub fields: BTreeMap<String, String>,
+}
+
 trait Combine<T> {
     fn combine(&mut self, other: T);
 }
@@ -274,6 +374,21 @@ impl Combine<&Self> for MetaConfig {
         if self.other.filename.is_none() {
             self.other.filename = other.other.filename.clone();
         }
+        if self.other.conflict.is_none() {
+            self.other.conflict = other.other.conflict;
+        }
+        if self.other.action.is_none() {
+            self.other.action = other.other.action;
+        }
+        if self.other.notation.is_none() {
+            self.other.notation = other.other.notation;
+        }
+        if self.temp_extensions.is_none() {
+            self.temp_extensions = other.temp_extensions.clone();
+        }
+        if self.settle.is_none() {
+            self.settle = other.settle;
+        }
     }
 }
 
@@ -284,6 +399,15 @@ impl Combine<&Schema> for MetaConfig {
             if schema_config.filename.is_none() {
                 self.other.filename = other.filename.clone();
             }
+            if schema_config.conflict.is_none() {
+                self.other.conflict = other.conflict;
+            }
+            if schema_config.action.is_none() {
+                self.other.action = other.action;
+            }
+            if schema_config.notation.is_none() {
+                self.other.notation = other.notation;
+            }
 
             // fields
             let keyslist: CommaSeperated = other.fields.keys().cloned().collect();
@@ -304,7 +428,7 @@ impl Combine<&Schema> for MetaConfig {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Default)]
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
 pub struct Config {
     #[serde(rename = "_schema")]
     #[serde(default)]
@@ -321,10 +445,27 @@ pub struct Config {
     #[serde(rename = "_meta")]
     #[serde(default)]
     pub meta: MetaConfig,
+    /// view name: search query, see views.rs
+    #[serde(rename = "_views")]
+    #[serde(default)]
+    pub views: HashMap<String, String>,
     #[serde(flatten)]
     pub uncategorized: Value,
 }
 
+impl MetaConfig {
+    pub fn temp_extensions(&self) -> Vec<String> {
+        match &self.temp_extensions {
+            Some(extensions) => extensions.0.clone(),
+            None => TEMP_EXTENSIONS.iter().map(|e| e.to_string()).collect(),
+        }
+    }
+
+    pub fn settle(&self) -> Duration {
+        Duration::from_secs_f64(self.settle.unwrap_or(DEFAULT_SETTLE).max(0.))
+    }
+}
+
 impl Config {
     pub fn combine_config(&mut self, other: &Config, higher_priority: bool) {
         // combine schema
@@ -358,21 +499,29 @@ impl Config {
             }
         }
 
-        // combine import
-        for i in other.import.list.iter() {
+        // combine views
+        for i in other.views.iter() {
             if higher_priority {
-                self.import.list.insert(0, i.clone());
-                continue;
+                self.views.insert(i.0.to_string(), i.1.clone());
             } else {
-                self.import.list.push(i.clone());
+                self.views.entry(i.0.to_string()).or_insert(i.1.clone());
             }
         }
 
+        // combine import, higher priority patterns are tried first (in their own order)
+        if higher_priority {
+            self.import
+                .list
+                .splice(0..0, other.import.list.iter().cloned());
+        } else {
+            self.import.list.extend(other.import.list.iter().cloned());
+        }
+
         // combine meta
-        // TODO: combine meta
         if higher_priority {
             let mut other_meta = other.meta.clone();
             other_meta.combine(&self.meta);
+            self.meta = other_meta;
         } else {
             self.meta.combine(&other.meta);
         }
@@ -1,15 +1,163 @@
 use chrono::{DateTime, Utc};
-use sqlx::Row;
-use sqlx::{query, sqlite::SqliteConnectOptions, Pool, Result, Sqlite, SqlitePool};
-use std::collections::HashMap;
+use sqlx::{
+    query,
+    sqlite::{SqliteConnectOptions, SqliteRow},
+    Pool, Result, Sqlite, SqliteConnection, SqlitePool, Transaction,
+};
+use sqlx::{Connection, Row};
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
-use crate::helper::{FileHelper, PathHelper};
-use crate::schema::Schema;
+use crate::config_reader::ConfigDatatype;
+use crate::error::FOError;
+use crate::fuzzy;
+use crate::helper::{FileHelper, FileState, PathHelper, HASH_CHUNK};
+use crate::schema::{Schema, SchemaList};
+use crate::search::{FuzzyMatches, Operation};
 
 // use rusqlite::{Connection, ErrorCode};
 
+/// files_fts row of files, add `WHERE` for some files only
+const FTS_ROW: &str = "SELECT id, name, path, COALESCE((SELECT group_concat(field_value, ' ') \
+    FROM any WHERE any.file_id = files.id), '') FROM files";
+
+/// One step to bring fo.db up to date
+pub struct Migration {
+    pub description: &'static str,
+    /// found something when a database made before the migrations (user_version 0)
+    /// has the step already
+    done_if: Option<&'static str>,
+    /// statements separated by `;`
+    sql: &'static str,
+}
+
+/// steps to bring fo.db up to date, PRAGMA user_version is the number of the
+/// ones done. a new one goes at the end, the ones already there don't change
+pub const MIGRATIONS: [Migration; 10] = [
+    Migration {
+        description: "tables of the first release",
+        done_if: None,
+        sql: "CREATE TABLE IF NOT EXISTS typeList (
+            id    INTEGER PRIMARY KEY AUTOINCREMENT,
+            name  TEXT NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS files (
+            id          INTEGER PRIMARY KEY AUTOINCREMENT,
+            name        TEXT NOT NULL,
+            path        TEXT NOT NULL,
+            last_mod    TEXT NOT NULL,
+            is_folder   INTEGER NOT NULL DEFAULT 0,
+            type        INTEGER DEFAULT 0,
+            parent      INTEGER,
+            FOREIGN KEY (parent) REFERENCES files(id),
+            FOREIGN KEY (type) REFERENCES typeList(id)
+        );
+        INSERT OR REPLACE INTO typeList VALUES (0,\"any\");
+        INSERT OR IGNORE INTO files (id,name,path,last_mod,is_folder) VALUES (0,\"root\",\"/\",datetime('now'),1);
+        CREATE TABLE IF NOT EXISTS any (
+            id          INTEGER PRIMARY KEY AUTOINCREMENT,
+            field       TEXT NOT NULL,
+            field_value TEXT NOT NULL,
+            FOREIGN KEY (id) REFERENCES files(id)
+        );
+        CREATE TABLE IF NOT EXISTS schema (
+            id          INTEGER PRIMARY KEY AUTOINCREMENT,
+            name        TEXT NOT NULL UNIQUE,
+            format      TEXT NOT NULL
+        );",
+    },
+    // the action that put the file there
+    Migration {
+        description: "files.placement",
+        done_if: Some("SELECT 1 FROM pragma_table_info('files') WHERE name = 'placement'"),
+        sql: "ALTER TABLE files ADD COLUMN placement TEXT",
+    },
+    // any.id was the file id, so a file could only have one field.
+    // nothing was written there yet, it's safe to make it again
+    Migration {
+        description: "any.file_id, more than one field for a file",
+        done_if: Some("SELECT 1 FROM pragma_table_info('any') WHERE name = 'file_id'"),
+        sql: "DROP TABLE IF EXISTS any;
+        CREATE TABLE any (
+            id          INTEGER PRIMARY KEY AUTOINCREMENT,
+            file_id     INTEGER NOT NULL,
+            field       TEXT NOT NULL,
+            field_value TEXT NOT NULL,
+            FOREIGN KEY (file_id) REFERENCES files(id)
+        );",
+    },
+    // rowid is files.id
+    Migration {
+        description: "files_fts for the full text search",
+        done_if: Some("SELECT 1 FROM sqlite_master WHERE name = 'files_fts'"),
+        sql: "CREATE VIRTUAL TABLE files_fts USING fts5(
+            name, path, fields,
+            tokenize = \"unicode61 separators '_.-[]()'\"
+        );
+        INSERT INTO files_fts(rowid,name,path,fields) SELECT id, name, path, \
+        COALESCE((SELECT group_concat(field_value, ' ') FROM any WHERE any.file_id = files.id), '') \
+        FROM files;",
+    },
+    Migration {
+        description: "journal of the organizer",
+        done_if: None,
+        sql: "CREATE TABLE IF NOT EXISTS journal (
+            id          INTEGER PRIMARY KEY AUTOINCREMENT,
+            run_id      INTEGER NOT NULL,
+            source      TEXT NOT NULL,
+            destination TEXT NOT NULL,
+            moved_at    TEXT NOT NULL,
+            pattern     TEXT NOT NULL,
+            schema      TEXT NOT NULL,
+            resolution  TEXT NOT NULL DEFAULT 'move',
+            action      TEXT NOT NULL DEFAULT 'move',
+            size        INTEGER NOT NULL,
+            last_mod    TEXT NOT NULL,
+            undone      INTEGER NOT NULL DEFAULT 0
+        );",
+    },
+    // partial_hash is set by the indexer, hash only when it's asked for
+    Migration {
+        description: "files.size and the content hashes",
+        done_if: None,
+        sql: "ALTER TABLE files ADD COLUMN size INTEGER;
+        ALTER TABLE files ADD COLUMN hash TEXT;
+        ALTER TABLE files ADD COLUMN partial_hash TEXT;",
+    },
+    Migration {
+        description: "files.device and files.inode",
+        done_if: None,
+        sql: "ALTER TABLE files ADD COLUMN device INTEGER;
+        ALTER TABLE files ADD COLUMN inode INTEGER;",
+    },
+    // the last time verify found the content the same as the hash
+    Migration {
+        description: "files.verified_at",
+        done_if: None,
+        sql: "ALTER TABLE files ADD COLUMN verified_at TEXT",
+    },
+    // a folder, a path or the fields of a file were found by reading the whole table
+    Migration {
+        description: "indexes on files.parent, files.path, files.size and any.file_id",
+        done_if: None,
+        sql: "CREATE INDEX IF NOT EXISTS files_parent ON files(parent);
+        CREATE INDEX IF NOT EXISTS files_path ON files(path);
+        CREATE INDEX IF NOT EXISTS files_size ON files(size);
+        CREATE INDEX IF NOT EXISTS any_file_id ON any(file_id);",
+    },
+    // the search with typos look for close words here instead of reading every file
+    Migration {
+        description: "files_vocab, the words of files_fts",
+        done_if: None,
+        sql: "CREATE VIRTUAL TABLE IF NOT EXISTS files_vocab USING fts5vocab(files_fts, row)",
+    },
+];
+
+/// words of files_fts tried for each word of a search with typos
+const FUZZY_WORDS: usize = 20;
+
 pub struct IndexDB {
     pool: Pool<Sqlite>,
     path: PathBuf,
@@ -24,7 +172,90 @@ pub struct ChildItem {
 }
 
 // id, other data
-type ChildrenList = HashMap<String, ChildItem>;
+pub type ChildrenList = HashMap<String, ChildItem>;
+
+/// A move done by the organizer, paths are relative to the db folder
+#[derive(Debug)]
+pub struct JournalEntry {
+    pub id: i64,
+    pub source: String,
+    pub destination: String,
+    pub moved_at: DateTime<Utc>,
+    pub pattern: String,
+    pub schema: String,
+    /// move, rename, overwrite or dedupe
+    pub resolution: String,
+    /// move, copy, hardlink, symlink or relative-symlink
+    pub action: String,
+    /// state of the file right after it was moved
+    pub state: FileState,
+    pub undone: bool,
+}
+
+/// a file found by search
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchHit {
+    pub id: i32,
+    pub name: String,
+    pub path: String,
+    pub last_modified: DateTime<Utc>,
+    pub is_folder: bool,
+}
+
+/// values of a field in search results, (value, number of files)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Facet {
+    pub field: String,
+    pub counts: Vec<(String, usize)>,
+}
+
+/// what is known of the content of a file, md5 in base64
+#[derive(Debug, Clone, PartialEq)]
+pub struct ContentHash {
+    pub size: i64,
+    /// of the size, the first and the last chunk, see FileHelper::partial_md5
+    pub partial: String,
+    pub full: Option<String>,
+}
+
+/// where a row is on the disk and what is in it, to find it again after a move
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileIdentity {
+    pub id: i32,
+    pub path: String,
+    pub is_folder: bool,
+    pub device: Option<i64>,
+    pub inode: Option<i64>,
+    pub size: Option<i64>,
+    pub partial_hash: Option<String>,
+    pub hash: Option<String>,
+}
+
+/// a row of files as the indexer found it on the disk
+#[derive(Debug, Clone)]
+pub struct FileRow {
+    pub path: String,
+    pub name: String,
+    pub last_mod: DateTime<Utc>,
+    pub is_folder: bool,
+    pub size: Option<i64>,
+    pub device: Option<i64>,
+    pub inode: Option<i64>,
+    pub partial_hash: Option<String>,
+}
+
+/// Writes of the indexer in one transaction, nothing is kept unless commit
+pub struct IndexBatch {
+    tx: Transaction<'static, Sqlite>,
+}
+
+#[derive(Debug)]
+pub struct JournalRun {
+    pub run_id: i64,
+    pub started_at: DateTime<Utc>,
+    pub moves: i64,
+    pub undone: i64,
+}
 
 impl IndexDB {
     pub async fn new() -> Result<Self> {
@@ -39,220 +270,745 @@ impl IndexDB {
         })
     }
 
-    /// Open a database file
+    /// Open a database file, brought up to date with the migrations
     /// note: use a path to directory not ./fo.db
     pub async fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
-        dbg!(&path.as_ref());
-        let path = PathBuf::from(path.as_ref());
-        let mut path = path.join("fo.db");
-        let db_exists = path.exists();
+        let db = Self::connect(path).await?;
+        db.migrate().await?;
+        Ok(db)
+    }
+
+    /// (version, description) of the migrations not done in the fo.db of the
+    /// folder. none if there is no fo.db, it's not made to look at it
+    pub async fn pending_in<P: AsRef<Path>>(path: P) -> Result<Vec<(usize, &'static str)>> {
+        if !path.as_ref().join("fo.db").exists() {
+            return Ok(vec![]);
+        }
+        let db = Self::connect(path).await?;
+        let pending = db.pending().await;
+        db.pool.close().await;
+        pending
+    }
+
+    /// Open a database file as it is, without migrating it. the root is kept
+    /// canonical, so the paths made from it don't depend on how it was written
+    pub async fn connect<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut path = std::fs::canonicalize(path)?.join("fo.db");
 
         // connection
         let db_path = "sqlite://".to_owned() + path.to_str().unwrap();
-        println!("connecting to {}", &db_path);
         let a = SqliteConnectOptions::from_str(&db_path)?
             .create_if_missing(true)
             .read_only(false);
 
         let pool = SqlitePool::connect_with(a).await?;
-
-        // setup db if not exists
         path.pop();
-        let mut db = Self { pool, path };
-        if !db_exists {
-            db.setup().await?;
+        Ok(Self { pool, path })
+    }
+
+    /// number of migrations done, PRAGMA user_version
+    pub async fn version(&self) -> Result<usize> {
+        let row = query("PRAGMA user_version").fetch_one(&self.pool).await?;
+        Ok(row.get::<i64, usize>(0) as usize)
+    }
+
+    /// (version, description) of the migrations not done yet
+    pub async fn pending(&self) -> Result<Vec<(usize, &'static str)>> {
+        let version = self.version().await?;
+        Ok((version + 1..=MIGRATIONS.len())
+            .map(|version| (version, MIGRATIONS[version - 1].description))
+            .collect())
+    }
+
+    /// Run the pending migrations, each in a transaction. fo.db is copied to
+    /// fo.db.v<version>.bak first unless it's a new one
+    pub async fn migrate(&self) -> Result<Vec<(usize, &'static str)>> {
+        let pending = self.pending().await?;
+        if pending.is_empty() {
+            return Ok(pending);
         }
-        Ok(db)
+        let has_tables = query("SELECT 1 FROM sqlite_master WHERE name = 'files'")
+            .fetch_optional(&self.pool)
+            .await?
+            .is_some();
+        if has_tables {
+            let backup = self.backup().await?;
+            eprintln!("migrating, the old database is in {:?}", backup);
+        }
+
+        // one connection, the others would keep columns of the old tables
+        // for the statements they have already
+        let mut conn = self.pool.acquire().await?;
+        for (version, _) in &pending {
+            let migration = &MIGRATIONS[version - 1];
+            let mut tx = conn.begin().await?;
+            let done = match migration.done_if {
+                Some(done_if) => query(done_if).fetch_optional(&mut tx).await?.is_some(),
+                None => false,
+            };
+            if !done {
+                query(migration.sql).execute(&mut tx).await?;
+            }
+            query(&format!("PRAGMA user_version = {}", version))
+                .execute(&mut tx)
+                .await?;
+            tx.commit().await?;
+        }
+        Ok(pending)
     }
 
-    pub async fn setup(&mut self) -> Result<()> {
-        dbg!("setting up the database...");
-        // "typeList" table
-        query(
-            "CREATE TABLE IF NOT EXISTS typeList (
-            id    INTEGER PRIMARY KEY AUTOINCREMENT,
-            name  TEXT NOT NULL
-        );",
+    /// a copy of fo.db as it is now, next to it
+    pub async fn backup(&self) -> Result<PathBuf> {
+        let backup = self
+            .path
+            .join(format!("fo.db.v{}.bak", self.version().await?));
+        if backup.exists() {
+            std::fs::remove_file(&backup)?;
+        }
+        // a plain copy could miss what is still in the wal file
+        query("VACUUM INTO ?")
+            .bind(backup.to_str().unwrap())
+            .execute(&self.pool)
+            .await?;
+        Ok(backup)
+    }
+
+    pub async fn add_file<P: AsRef<Path>>(&self, path: P, parent: i32) -> Result<i32> {
+        // get file
+        let path = PathBuf::from(path.as_ref());
+        let full_path = self.get_path_new().join(&path);
+
+        // get size, the hashes are made by content_hash
+        let size = FileHelper::new(&full_path)
+            .state()
+            .ok()
+            .map(|state| state.size);
+
+        // get last mod
+        let last_mod = FileHelper::new(&full_path)
+            .last_mod()
+            .unwrap_or(DateTime::from(Utc::now()));
+
+        // insert
+        let result = query(
+            "INSERT OR REPLACE INTO files(path,name,last_mod,parent,size) VALUES (?,?,?,?,?)",
         )
+        .bind(path.format())
+        .bind(path.file_name().unwrap().to_str().unwrap())
+        .bind(last_mod.naive_utc().format("%Y-%m-%d %H:%M:%S").to_string())
+        .bind(parent)
+        .bind(size)
         .execute(&self.pool)
         .await?;
+        let id = result.last_insert_rowid() as i32;
+        self.set_inode(id, &full_path).await?;
+        self.refresh_search(id).await?;
+        Ok(id)
+    }
 
-        // "file" table
-        dbg!("file table");
-        query(
-            "CREATE TABLE IF NOT EXISTS files (
-            id          INTEGER PRIMARY KEY AUTOINCREMENT,
-            name        TEXT NOT NULL,
-            path        TEXT NOT NULL,
-            last_mod    TEXT NOT NULL,
-            is_folder   INTEGER NOT NULL DEFAULT 0,
-            type        INTEGER DEFAULT 0,
-            parent      INTEGER,
-            FOREIGN KEY (parent) REFERENCES files(id),
-            FOREIGN KEY (type) REFERENCES typeList(id)
-        );",
+    async fn set_inode(&self, id: i32, full_path: &Path) -> Result<()> {
+        let (device, inode) = FileHelper::new(full_path).inode().unzip();
+        query("UPDATE files SET device = ?, inode = ? WHERE id = ?")
+            .bind(device)
+            .bind(inode)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Write the full text search row of a file again, after its name or fields changed
+    pub async fn refresh_search(&self, id: i32) -> Result<()> {
+        refresh_search(&mut *self.pool.acquire().await?, id).await
+    }
+
+    /// delete a file, or a folder with everything in it
+    pub async fn delete(&self, id: i32) -> Result<()> {
+        delete(&mut *self.pool.acquire().await?, id).await
+    }
+
+    /// Files and folders matching the query, best first. Unless exact,
+    /// the values without field also match with typos
+    pub async fn search(
+        &self,
+        operation: &Operation,
+        schemalist: &SchemaList,
+        exact: bool,
+    ) -> std::result::Result<Vec<SearchHit>, FOError> {
+        let mut fuzzy = FuzzyMatches::new();
+        if !exact {
+            for value in operation.values() {
+                if let Some(close) = self.close_words(value).await? {
+                    fuzzy.insert(value.to_owned(), close);
+                }
+            }
+        }
+        let score = |value: &str, name: &str, other: &str| {
+            let name = fuzzy::score(value, name);
+            // a name match weight more, like in bm25 below
+            let other = fuzzy::score(value, other).map(|score| score / 2.0);
+            match (name, other) {
+                (Some(name), Some(other)) => Some(name.max(other)),
+                (name, other) => name.or(other),
+            }
+        };
+        let (condition, mut params) = operation.to_sql(schemalist, &fuzzy)?;
+        // best matches of the words first, a name match weight more than a path or field match
+        let sql = match operation.rank_query() {
+            Some(rank_query) => {
+                params.insert(0, rank_query);
+                format!(
+                    "SELECT id, files.name, files.path, last_mod, is_folder, \
+                    text.path || ' ' || text.fields AS other FROM files \
+                    LEFT JOIN (SELECT rowid, bm25(files_fts, 10.0, 2.0, 1.0) AS rank \
+                    FROM files_fts WHERE files_fts MATCH ?) AS ranked ON ranked.rowid = files.id \
+                    LEFT JOIN files_fts AS text ON text.rowid = files.id \
+                    WHERE id != 0 AND {} ORDER BY ranked.rank IS NULL, ranked.rank, files.path",
+                    condition
+                )
+            }
+            None => format!(
+                "SELECT id, name, path, last_mod, is_folder, '' AS other FROM files \
+                WHERE id != 0 AND {} ORDER BY path",
+                condition
+            ),
+        };
+        let mut search = query(&sql);
+        for param in params {
+            search = search.bind(param);
+        }
+        let mut found = search
+            .fetch_all(&self.pool)
+            .await?
+            .iter()
+            .map(|row| {
+                let hit = SearchHit {
+                    id: row.get::<i32, &str>("id"),
+                    name: row.get::<String, &str>("name"),
+                    path: row.get::<String, &str>("path"),
+                    last_modified: row.get::<DateTime<Utc>, &str>("last_mod"),
+                    is_folder: row.get::<bool, &str>("is_folder"),
+                };
+                (hit, row.get::<Option<String>, &str>("other"))
+            })
+            .collect::<Vec<_>>();
+
+        // the closest first, bm25 order is kept for the same score
+        if !exact {
+            let values = operation.rank_values();
+            found.sort_by_cached_key(|(hit, other)| {
+                let other = other.as_deref().unwrap_or_default();
+                let total: f64 = values
+                    .iter()
+                    .filter_map(|value| score(value, &hit.name, other))
+                    .sum();
+                // positive, the bits sort the same way
+                Reverse(total.to_bits())
+            });
+        }
+        Ok(found.into_iter().map(|(hit, _)| hit).collect())
+    }
+
+    /// MATCH for files_fts with the words close to the ones of the value, None
+    /// if one of them has none. like most typo search, the first letter has to
+    /// be right, only the words starting with it are read
+    async fn close_words(&self, value: &str) -> Result<Option<String>> {
+        let mut words = vec![];
+        for word in fuzzy::words(value) {
+            let first = word.chars().next().unwrap();
+            let next = char::from_u32(first as u32 + 1).unwrap_or(char::MAX);
+            let mut close = query("SELECT term FROM files_vocab WHERE term >= ? AND term < ?")
+                .bind(first.to_string())
+                .bind(next.to_string())
+                .fetch_all(&self.pool)
+                .await?
+                .iter()
+                .filter_map(|row| {
+                    let term = row.get::<String, &str>("term");
+                    fuzzy::word_score(&word, &term).map(|score| (score, term))
+                })
+                .collect::<Vec<_>>();
+            if close.is_empty() {
+                return Ok(None);
+            }
+            close.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+            let close = close
+                .iter()
+                .take(FUZZY_WORDS)
+                .map(|(_, term)| format!("\"{}\"", term.replace('"', "\"\"")))
+                .collect::<Vec<_>>();
+            words.push(format!("({})", close.join(" OR ")));
+        }
+        Ok(match words.is_empty() {
+            true => None,
+            false => Some(words.join(" AND ")),
+        })
+    }
+
+    /// How many of the files have each value of the fields, most common first.
+    /// `ext` and `schema` are taken from the files table, the rest from any.
+    /// a list value (`a, b`) count for every item
+    pub async fn facets<T: AsRef<str>>(&self, ids: &[i32], fields: &[T]) -> Result<Vec<Facet>> {
+        let ids = ids.iter().map(|id| id.to_string()).collect::<Vec<_>>();
+        let ids = ids.join(",");
+        let mut facets = vec![];
+        for field in fields {
+            let field = field.as_ref();
+            // (file id, value)
+            let (rows, by_field) = match field.to_lowercase().as_str() {
+                "ext" => (format!(
+                    "SELECT id AS file_id, name AS value FROM files WHERE is_folder = 0 AND id IN ({})",
+                    ids
+                ), false),
+                "schema" => (format!(
+                    "SELECT files.id AS file_id, typeList.name AS value FROM files \
+                    JOIN typeList ON typeList.id = files.type WHERE files.id IN ({})",
+                    ids
+                ), false),
+                _ => (format!(
+                    "SELECT file_id, field_value AS value FROM any \
+                    WHERE field = ? COLLATE NOCASE AND file_id IN ({})",
+                    ids
+                ), true),
+            };
+            let mut rows = query(&rows);
+            if by_field {
+                rows = rows.bind(field);
+            }
+            let rows = rows.fetch_all(&self.pool).await?;
+
+            let mut counts: HashMap<String, HashSet<i32>> = HashMap::new();
+            for row in rows {
+                let value = row.get::<String, &str>("value");
+                let values = match field.eq_ignore_ascii_case("ext") {
+                    true => Path::new(&value)
+                        .extension()
+                        .map(|ext| vec![ext.to_string_lossy().to_lowercase()])
+                        .unwrap_or_default(),
+                    false => value
+                        .split(',')
+                        .map(|value| value.trim().to_owned())
+                        .filter(|value| !value.is_empty())
+                        .collect(),
+                };
+                for value in values {
+                    counts
+                        .entry(value)
+                        .or_default()
+                        .insert(row.get::<i32, &str>("file_id"));
+                }
+            }
+            let mut counts = counts
+                .into_iter()
+                .map(|(value, files)| (value, files.len()))
+                .collect::<Vec<_>>();
+            counts.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then(a.cmp(b)));
+            facets.push(Facet {
+                field: field.to_owned(),
+                counts,
+            });
+        }
+        Ok(facets)
+    }
+
+    /// children of every folder by its id, to compare the whole tree with the disk
+    pub async fn all_children(&self) -> Result<HashMap<i32, ChildrenList>> {
+        let mut tree: HashMap<i32, ChildrenList> = HashMap::new();
+        let rows = query(
+            "SELECT id, name, path, last_mod, is_folder, parent FROM files WHERE parent IS NOT NULL",
         )
-        .execute(&self.pool)
+        .fetch_all(&self.pool)
+        .await?;
+        for row in &rows {
+            tree.entry(row.get::<i32, &str>("parent"))
+                .or_default()
+                .insert(row.get::<String, &str>("name"), child_item(row));
+        }
+        Ok(tree)
+    }
+
+    pub async fn batch(&self) -> Result<IndexBatch> {
+        Ok(IndexBatch {
+            tx: self.pool.begin().await?,
+        })
+    }
+
+    /// fields of a file in the any table, in the order they were added
+    pub async fn fields(&self, id: i32) -> Result<Vec<(String, String)>> {
+        fields(&mut *self.pool.acquire().await?, id).await
+    }
+
+    /// Size and hashes of a file, the ones not in the row yet are made and
+    /// kept. only the partial one unless full. None for a folder
+    pub async fn content_hash(
+        &self,
+        id: i32,
+        full: bool,
+    ) -> std::result::Result<Option<ContentHash>, FOError> {
+        let row = query(
+            "SELECT path, is_folder, size, partial_hash, hash FROM files WHERE id = ? AND id != 0",
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+        let Some(row) = row else {
+            return Ok(None);
+        };
+        if row.get::<bool, &str>("is_folder") {
+            return Ok(None);
+        }
+        let mut hash = ContentHash {
+            size: row.get::<Option<i64>, &str>("size").unwrap_or_default(),
+            partial: row
+                .get::<Option<String>, &str>("partial_hash")
+                .unwrap_or_default(),
+            full: row.get::<Option<String>, &str>("hash"),
+        };
+        let stored = hash.clone();
+
+        // a file indexed before the hashes were there
+        let file_helper = FileHelper::new(self.absolute(&row.get::<String, &str>("path")));
+        if hash.partial.is_empty() {
+            hash.size = file_helper.state()?.size;
+            hash.partial = file_helper.partial_md5()?;
+        }
+        if full && hash.full.is_none() {
+            // the partial one is the whole file already
+            hash.full = match hash.size as u64 <= 2 * HASH_CHUNK {
+                true => Some(hash.partial.to_owned()),
+                false => Some(file_helper.md5()?),
+            };
+        }
+        if hash != stored {
+            query("UPDATE files SET size = ?, partial_hash = ?, hash = ? WHERE id = ?")
+                .bind(hash.size)
+                .bind(&hash.partial)
+                .bind(&hash.full)
+                .bind(id)
+                .execute(&self.pool)
+                .await?;
+        }
+        Ok(Some(hash))
+    }
+
+    /// Identity of the rows with everything inside the folders among them
+    pub async fn identities(&self, ids: &[i32]) -> Result<Vec<FileIdentity>> {
+        let ids = ids.iter().map(|id| id.to_string()).collect::<Vec<_>>();
+        let rows = query(&format!(
+            "WITH RECURSIVE tree(id) AS (SELECT id FROM files WHERE id IN ({}) \
+            UNION SELECT files.id FROM files JOIN tree ON files.parent = tree.id) \
+            SELECT files.id, path, is_folder, device, inode, size, partial_hash, hash \
+            FROM files JOIN tree ON tree.id = files.id WHERE files.id != 0 ORDER BY path",
+            ids.join(",")
+        ))
+        .fetch_all(&self.pool)
         .await?;
+        Ok(rows.iter().map(identity).collect())
+    }
+
+    /// Files to verify with their last_mod, the ones never verified first then
+    /// the ones verified the longest time ago
+    pub async fn verify_queue(&self) -> Result<Vec<(FileIdentity, DateTime<Utc>)>> {
+        let rows = query(
+            "SELECT id, path, is_folder, device, inode, size, partial_hash, hash, last_mod FROM files \
+            WHERE id != 0 AND is_folder = 0 ORDER BY verified_at IS NOT NULL, verified_at, id",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows
+            .iter()
+            .map(|row| (identity(row), row.get::<DateTime<Utc>, &str>("last_mod")))
+            .collect())
+    }
 
-        // add typelist
-        dbg!("add typelist");
-        query("INSERT OR REPLACE INTO typeList VALUES (0,\"any\");")
+    /// the content of the file was checked now, hash is set if there was none
+    pub async fn set_verified(&self, id: i32, hash: Option<&str>) -> Result<()> {
+        query("UPDATE files SET verified_at = ?, hash = COALESCE(?, hash) WHERE id = ?")
+            .bind(Utc::now())
+            .bind(hash)
+            .bind(id)
             .execute(&self.pool)
             .await?;
+        Ok(())
+    }
 
-        // add folder file
-        dbg!("add folder");
-        query("INSERT OR REPLACE INTO files (id,name,path,last_mod,is_folder) VALUES (0,\"root\",\"/\",datetime('now'),1);")
+    /// The row old is moved to where the row new is and new is deleted, so old
+    /// keep its id and fields. the files in new go to old
+    pub async fn take_over(&self, old: i32, new: i32) -> Result<()> {
+        query("UPDATE files SET parent = ? WHERE parent = ?")
+            .bind(old)
+            .bind(new)
             .execute(&self.pool)
             .await?;
-
-        // "any" table
-        dbg!("any");
+        // the full hash is kept if the content is still the same, a file edited
+        // in the middle has the same partial hash but not the same last_mod
         query(
-            "CREATE TABLE IF NOT EXISTS any (
-            id          INTEGER PRIMARY KEY AUTOINCREMENT,
-            field       TEXT NOT NULL,
-            field_value TEXT NOT NULL,
-            FOREIGN KEY (id) REFERENCES files(id)
-        );",
+            "UPDATE files SET (path, name, parent, last_mod, size, device, inode, partial_hash, hash) = \
+            (SELECT path, name, parent, last_mod, size, device, inode, partial_hash, \
+            CASE WHEN new.partial_hash IS files.partial_hash AND new.last_mod IS files.last_mod \
+            THEN COALESCE(new.hash, files.hash) ELSE new.hash END \
+            FROM files AS new WHERE new.id = ?) WHERE id = ?",
         )
+        .bind(new)
+        .bind(old)
         .execute(&self.pool)
         .await?;
+        self.delete(new).await?;
+        self.refresh_search(old).await
+    }
 
-        // "schema"
-        query(
-            "CREATE TABLE IF NOT EXISTS schema (
-            id          INTEGER PRIMARY KEY AUTOINCREMENT,
-            name        TEXT NOT NULL UNIQUE,
-            format      TEXT NOT NULL
-        );",
+    /// Files with the same content, found by size, then the partial hash, then
+    /// the full one. hard links of one file are not copies, a group has two
+    /// files or more
+    pub async fn duplicates(&self) -> std::result::Result<Vec<Vec<FileIdentity>>, FOError> {
+        // indexed before the sizes were kept
+        let unknown =
+            query("SELECT id FROM files WHERE id != 0 AND is_folder = 0 AND size IS NULL")
+                .fetch_all(&self.pool)
+                .await?;
+        for row in unknown {
+            // gone since, the indexer will find out
+            match self.content_hash(row.get::<i32, &str>("id"), false).await {
+                Ok(_) | Err(FOError::IOError(_)) => (),
+                Err(e) => return Err(e),
+            }
+        }
+
+        let sizes = query(
+            "SELECT size FROM files WHERE id != 0 AND is_folder = 0 AND size > 0 \
+            GROUP BY size HAVING COUNT(*) > 1 ORDER BY size DESC",
         )
-        .execute(&self.pool)
+        .fetch_all(&self.pool)
         .await?;
-
-        Ok(())
+        let mut groups = vec![];
+        for size in sizes {
+            let ids = query("SELECT id FROM files WHERE is_folder = 0 AND size = ?")
+                .bind(size.get::<i64, &str>("size"))
+                .fetch_all(&self.pool)
+                .await?
+                .iter()
+                .map(|row| row.get::<i32, &str>("id"))
+                .collect::<Vec<_>>();
+            let mut by_partial: HashMap<String, Vec<i32>> = HashMap::new();
+            for id in ids {
+                match self.content_hash(id, false).await {
+                    Ok(Some(hash)) => by_partial.entry(hash.partial).or_default().push(id),
+                    Ok(None) | Err(FOError::IOError(_)) => (),
+                    Err(e) => return Err(e),
+                }
+            }
+            for ids in by_partial.into_values().filter(|ids| ids.len() > 1) {
+                let mut by_full: HashMap<String, Vec<i32>> = HashMap::new();
+                for id in ids {
+                    match self.content_hash(id, true).await {
+                        Ok(Some(ContentHash {
+                            full: Some(full), ..
+                        })) => by_full.entry(full).or_default().push(id),
+                        Ok(_) | Err(FOError::IOError(_)) => (),
+                        Err(e) => return Err(e),
+                    }
+                }
+                for ids in by_full.into_values() {
+                    let group = self.identities(&ids).await?;
+                    let inodes = group
+                        .iter()
+                        .map(|file| match file.inode {
+                            Some(inode) => (file.device, Some(inode), 0),
+                            None => (None, None, file.id),
+                        })
+                        .collect::<HashSet<_>>();
+                    if inodes.len() > 1 {
+                        groups.push(group);
+                    }
+                }
+            }
+        }
+        Ok(groups)
     }
 
-    pub async fn add_file<P: AsRef<Path>>(&self, path: P, parent: i32) -> Result<()> {
-        // get file
-        dbg!("getfile");
-        let path = PathBuf::from(path.as_ref());
-        let full_path = self.get_path_new().join(&path);
+    /// typeList name of a file, the last schema it went through or any
+    pub async fn file_type(&self, id: i32) -> Result<String> {
+        let row = query(
+            "SELECT typeList.name FROM files JOIN typeList ON typeList.id = files.type WHERE files.id = ?",
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map_or("any".to_owned(), |row| row.get::<String, &str>("name")))
+    }
 
-        // get hash
-        // dbg!("hash");
-        // let mut hasher = md5::Md5::default();
-        // std::io::copy(&mut file, &mut hasher)?;
-        // let hash = Base64::encode_string(&hasher.finalize());
+    /// a files.path on disk, files.path is relative to the db folder
+    pub fn absolute(&self, path: &str) -> PathBuf {
+        self.path.join(path.trim_start_matches("./"))
+    }
 
-        // get last mod
-        dbg!("lastmod");
-        let last_mod = FileHelper::new(&full_path)
-            .last_mod()
-            .unwrap_or(DateTime::from(Utc::now()));
+    pub fn exists(&self) -> bool {
+        self.path.exists()
+    }
 
-        dbg!(&last_mod);
+    pub fn get_path_new(&self) -> PathBuf {
+        PathBuf::from(&self.path)
+        // (&self.path).to_owned().as_path()
+    }
 
-        // insert
-        dbg!("insert");
-        query("INSERT OR REPLACE INTO files(path,name,last_mod,parent) VALUES (?,?,?,?)")
-            .bind(path.format())
-            .bind(path.file_name().unwrap().to_str().unwrap())
-            .bind(last_mod.naive_utc().format("%Y-%m-%d %H:%M:%S").to_string())
-            // .bind(hash)
-            .bind(parent)
-            .execute(&self.pool)
+    /// id of the row with this path, path is in the same format as files.path
+    pub async fn find_path(&self, path: &str) -> Result<Option<i32>> {
+        let row = query("SELECT id FROM files WHERE path = ?")
+            .bind(path)
+            .fetch_optional(&self.pool)
             .await?;
-        Ok(())
+        Ok(row.map(|row| row.get::<i32, &str>("id")))
     }
 
-    pub async fn add_folder<P: AsRef<Path>>(&self, path: P, parent: i32) -> Result<i32> {
-        // get folder
+    /// Add a file without indexing the whole folder, the folders above it are added too.
+    /// path is relative to the db folder
+    pub async fn index_path<P: AsRef<Path>>(&self, path: P) -> Result<i32> {
         let path = PathBuf::from(path.as_ref());
-        let full_path = self.get_path_new().join(&path);
-
-        // get last mod
-        let last_mod = FileHelper::new(&full_path)
-            .last_mod()
-            .unwrap_or(DateTime::from(Utc::now()));
+        let mut parent = 0;
+        let mut current = PathBuf::from("./");
+        let components = path.iter().filter(|c| !c.eq(&".")).collect::<Vec<_>>();
+        for (i, component) in components.iter().enumerate() {
+            current.push(component);
+            if let Some(id) = self.find_path(&current.format()).await? {
+                parent = id;
+                continue;
+            }
+            parent = if i + 1 == components.len() {
+                self.add_file(&current, parent).await?
+            } else {
+                // the other files inside are not indexed yet,
+                // an old last_mod make sure the indexer will look inside it
+                let id = query(
+                    "INSERT INTO files(path,name,last_mod,parent,is_folder) VALUES (?,?,'1970-01-01 00:00:00',?,1)",
+                )
+                .bind(current.format())
+                .bind(component.to_str().unwrap())
+                .bind(parent)
+                .execute(&self.pool)
+                .await?
+                .last_insert_rowid() as i32;
+                self.set_inode(id, &self.absolute(&current.format()))
+                    .await?;
+                self.refresh_search(id).await?;
+                id
+            };
+        }
+        Ok(parent)
+    }
 
-        // insert
-        dbg!("folder insert", path.file_name());
-        let result = query(
-            "INSERT OR REPLACE INTO files(path,name,last_mod,parent,is_folder) VALUES (?,?,?,?,1)",
-        )
-        .bind(path.format())
-        .bind(path.file_name().unwrap().to_str().unwrap())
-        .bind(last_mod.naive_utc().format("%Y-%m-%d %H:%M:%S").to_string())
-        .bind(parent)
-        .execute(&self.pool)
-        .await?;
-        Ok(result.last_insert_rowid() as i32)
+    pub async fn delete_path(&self, path: &str) -> Result<()> {
+        if let Some(id) = self.find_path(path).await? {
+            self.delete(id).await?;
+        }
+        Ok(())
     }
 
-    pub async fn delete(&self, id: i32) -> Result<()> {
-        // delete from file
-        query("DELETE FROM files WHERE id = ?")
-            .bind(&id)
+    pub async fn set_placement(&self, id: i32, placement: &str) -> Result<()> {
+        query("UPDATE files SET placement = ? WHERE id = ?")
+            .bind(placement)
+            .bind(id)
             .execute(&self.pool)
             .await?;
+        Ok(())
+    }
 
-        // delete from any
-        query("DELETE FROM any WHERE id = ?")
-            .bind(&id)
-            .execute(&self.pool)
+    /// path relative to the db folder, in the same format as files.path
+    pub fn relative_path<P: AsRef<Path>>(&self, path: P) -> String {
+        let path = path.as_ref();
+        let canonical = canonical(path);
+        let relative = canonical
+            .strip_prefix(&self.path)
+            .or_else(|_| path.strip_prefix(&self.path))
+            .unwrap_or(path);
+        PathBuf::from("./").join(relative).format()
+    }
+
+    pub async fn next_run_id(&self) -> Result<i64> {
+        let row = query("SELECT COALESCE(MAX(run_id), 0) + 1 AS run_id FROM journal")
+            .fetch_one(&self.pool)
             .await?;
-        Ok(())
+        Ok(row.get::<i64, &str>("run_id"))
     }
 
-    // pub async fn search(&self, keyword: &str) -> Result<Vec<File>> {
-    //     let mut data = query("SELECT * FROM files WHERE path LIKE ?")
-    //         .bind(format!("{}{}{}", "%", keyword, "%"))
-    //         // .map(|row|{
-    //         //     row.
-    //         // })
-    //         .fetch_all(&self.pool)
-    //         .await?
-    //         .iter()
-    //         .map(|row| File {
-    //             name: row.get::<String, &str>("name"),
-    //             path: row.get::<String, &str>("path"),
-    //         })
-    //         .collect();
-    //     Ok(data)
+    /// latest run that still have something to undo
+    pub async fn last_run_id(&self) -> Result<Option<i64>> {
+        let row = query("SELECT MAX(run_id) AS run_id FROM journal WHERE undone = 0")
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(row.get::<Option<i64>, &str>("run_id"))
+    }
 
-    //     // to add other data
-    // }
+    /// id and undone of the entry are ignored
+    pub async fn add_journal(&self, run_id: i64, entry: &JournalEntry) -> Result<()> {
+        query(
+            "INSERT INTO journal(run_id,source,destination,moved_at,pattern,schema,resolution,action,size,last_mod)
+            VALUES (?,?,?,?,?,?,?,?,?,?)",
+        )
+        .bind(run_id)
+        .bind(&entry.source)
+        .bind(&entry.destination)
+        .bind(entry.moved_at)
+        .bind(&entry.pattern)
+        .bind(&entry.schema)
+        .bind(&entry.resolution)
+        .bind(&entry.action)
+        .bind(entry.state.size)
+        .bind(entry.state.modified)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
 
-    pub async fn children(&self, id: i32) -> Result<ChildrenList> {
-        let data: ChildrenList = query("SELECT * FROM files WHERE parent = ?")
-            .bind(id)
+    /// entries of a run, the newest move first
+    pub async fn journal(&self, run_id: i64) -> Result<Vec<JournalEntry>> {
+        let data = query("SELECT * FROM journal WHERE run_id = ? ORDER BY id DESC")
+            .bind(run_id)
             .fetch_all(&self.pool)
             .await?
             .iter()
-            .map(|row| {
-                (
-                    row.get::<String, &str>("name"),
-                    ChildItem {
-                        id: row.get::<i32, &str>("id"),
-                        path: row.get::<String, &str>("path"),
-                        last_modified: row.get::<DateTime<Utc>, &str>("last_mod"),
-                        is_folder: row.get::<bool, &str>("is_folder"),
-                    },
-                )
+            .map(|row| JournalEntry {
+                id: row.get::<i64, &str>("id"),
+                source: row.get::<String, &str>("source"),
+                destination: row.get::<String, &str>("destination"),
+                moved_at: row.get::<DateTime<Utc>, &str>("moved_at"),
+                pattern: row.get::<String, &str>("pattern"),
+                schema: row.get::<String, &str>("schema"),
+                resolution: row.get::<String, &str>("resolution"),
+                action: row.get::<String, &str>("action"),
+                state: FileState {
+                    size: row.get::<i64, &str>("size"),
+                    modified: row.get::<DateTime<Utc>, &str>("last_mod"),
+                },
+                undone: row.get::<bool, &str>("undone"),
             })
             .collect();
         Ok(data)
     }
 
-    pub fn exists(&self) -> bool {
-        self.path.exists()
+    pub async fn journal_runs(&self) -> Result<Vec<JournalRun>> {
+        let data = query(
+            "SELECT run_id, MIN(moved_at) AS started_at, COUNT(*) AS moves, SUM(undone) AS undone
+            FROM journal GROUP BY run_id ORDER BY run_id DESC",
+        )
+        .fetch_all(&self.pool)
+        .await?
+        .iter()
+        .map(|row| JournalRun {
+            run_id: row.get::<i64, &str>("run_id"),
+            started_at: row.get::<DateTime<Utc>, &str>("started_at"),
+            moves: row.get::<i64, &str>("moves"),
+            undone: row.get::<i64, &str>("undone"),
+        })
+        .collect();
+        Ok(data)
     }
 
-    pub fn get_path_new(&self) -> PathBuf {
-        PathBuf::from(&self.path)
-        // (&self.path).to_owned().as_path()
+    pub async fn set_undone(&self, id: i64) -> Result<()> {
+        query("UPDATE journal SET undone = 1 WHERE id = ?")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
     }
 
     pub async fn save_schema(&self, schema: &Schema) {
@@ -264,6 +1020,21 @@ impl IndexDB {
             .unwrap();
     }
 
+    /// Make the table of every schema, or make it again if its fields changed
+    pub async fn sync_schemas(&self, schemalist: &SchemaList) -> Result<()> {
+        let mut conn = self.pool.acquire().await?;
+        for schema in schemalist.list.values() {
+            sync_schema(&mut conn, schema).await?;
+        }
+        Ok(())
+    }
+
+    /// schema_<name> with file_id and a column for each field. the files
+    /// already in an old table are put back from the any table
+    pub async fn sync_schema(&self, schema: &Schema) -> Result<()> {
+        sync_schema(&mut *self.pool.acquire().await?, schema).await
+    }
+
     // pub async fn get_schemas(&self) -> Result<Vec<Schema>> {
     //     let data = query("SELECT * FROM schema")
     //         .fetch_all(&self.pool)
@@ -275,6 +1046,414 @@ impl IndexDB {
     // }
 }
 
+impl IndexBatch {
+    /// Insert a file or folder with its fields, like add_file then add_fields
+    /// without reading the disk again
+    pub async fn add(
+        &mut self,
+        row: &FileRow,
+        parent: i32,
+        fields: &[(String, String)],
+    ) -> Result<i32> {
+        let id = query(
+            "INSERT OR REPLACE INTO files(path,name,last_mod,parent,is_folder,size,device,inode,partial_hash) \
+            VALUES (?,?,?,?,?,?,?,?,?)",
+        )
+        .bind(&row.path)
+        .bind(&row.name)
+        .bind(row.last_mod.naive_utc().format("%Y-%m-%d %H:%M:%S").to_string())
+        .bind(parent)
+        .bind(row.is_folder)
+        .bind(row.size)
+        .bind(row.device)
+        .bind(row.inode)
+        .bind(&row.partial_hash)
+        .execute(&mut *self.tx)
+        .await?
+        .last_insert_rowid() as i32;
+        add_fields(&mut self.tx, id, fields).await?;
+        Ok(id)
+    }
+
+    /// A file changed in place, its hashes are the ones of the old content
+    /// and the fields are extracted again
+    pub async fn update(
+        &mut self,
+        id: i32,
+        row: &FileRow,
+        fields: &[(String, String)],
+    ) -> Result<()> {
+        query(
+            "UPDATE files SET last_mod = ?, size = ?, device = ?, inode = ?, partial_hash = ?, \
+            hash = NULL WHERE id = ?",
+        )
+        .bind(
+            row.last_mod
+                .naive_utc()
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string(),
+        )
+        .bind(row.size)
+        .bind(row.device)
+        .bind(row.inode)
+        .bind(&row.partial_hash)
+        .bind(id)
+        .execute(&mut *self.tx)
+        .await?;
+        self.set_fields(id, fields).await
+    }
+
+    /// Put a file in the tables of the schemas it went through, files.type is
+    /// the last one. no schema is the any type. the tables are made by sync_schema before
+    pub async fn set_schemas(&mut self, id: i32, schemas: &[Schema]) -> Result<()> {
+        set_schemas(&mut self.tx, id, schemas).await
+    }
+
+    /// the fields of a file in place of the ones it had
+    pub async fn set_fields(&mut self, id: i32, fields: &[(String, String)]) -> Result<()> {
+        query("DELETE FROM any WHERE file_id = ?")
+            .bind(id)
+            .execute(&mut *self.tx)
+            .await?;
+        add_fields(&mut self.tx, id, fields).await
+    }
+
+    pub async fn delete(&mut self, id: i32) -> Result<()> {
+        delete(&mut self.tx, id).await
+    }
+
+    pub async fn commit(self) -> Result<()> {
+        self.tx.commit().await
+    }
+}
+
+/// the path with its folders canonical, the root can be written another way
+/// than the one of IndexDB. the file itself isn't, it can be a symlink, and
+/// what is not there yet is kept as it is
+fn canonical(path: &Path) -> PathBuf {
+    for ancestor in path.ancestors().skip(1) {
+        if let Ok(canonical) = std::fs::canonicalize(ancestor) {
+            let rest = path.strip_prefix(ancestor).unwrap_or(path);
+            return canonical.join(rest);
+        }
+    }
+    path.to_owned()
+}
+
+fn identity(row: &SqliteRow) -> FileIdentity {
+    FileIdentity {
+        id: row.get::<i32, &str>("id"),
+        path: row.get::<String, &str>("path"),
+        is_folder: row.get::<bool, &str>("is_folder"),
+        device: row.get::<Option<i64>, &str>("device"),
+        inode: row.get::<Option<i64>, &str>("inode"),
+        size: row.get::<Option<i64>, &str>("size"),
+        partial_hash: row.get::<Option<String>, &str>("partial_hash"),
+        hash: row.get::<Option<String>, &str>("hash"),
+    }
+}
+
+async fn children(conn: &mut SqliteConnection, id: i32) -> Result<ChildrenList> {
+    let data: ChildrenList =
+        query("SELECT id, name, path, last_mod, is_folder FROM files WHERE parent = ?")
+            .bind(id)
+            .fetch_all(&mut *conn)
+            .await?
+            .iter()
+            .map(|row| (row.get::<String, &str>("name"), child_item(row)))
+            .collect();
+    Ok(data)
+}
+
+fn child_item(row: &SqliteRow) -> ChildItem {
+    ChildItem {
+        id: row.get::<i32, &str>("id"),
+        path: row.get::<String, &str>("path"),
+        last_modified: row.get::<DateTime<Utc>, &str>("last_mod"),
+        is_folder: row.get::<bool, &str>("is_folder"),
+    }
+}
+
+async fn fields(conn: &mut SqliteConnection, id: i32) -> Result<Vec<(String, String)>> {
+    Ok(
+        query("SELECT field, field_value FROM any WHERE file_id = ? ORDER BY id")
+            .bind(id)
+            .fetch_all(&mut *conn)
+            .await?
+            .iter()
+            .map(|row| {
+                (
+                    row.get::<String, &str>("field"),
+                    row.get::<String, &str>("field_value"),
+                )
+            })
+            .collect(),
+    )
+}
+
+async fn type_id(conn: &mut SqliteConnection, name: &str) -> Result<i64> {
+    let name = name.to_lowercase();
+    let row = query("SELECT id FROM typeList WHERE name = ?")
+        .bind(&name)
+        .fetch_optional(&mut *conn)
+        .await?;
+    if let Some(row) = row {
+        return Ok(row.get::<i64, &str>("id"));
+    }
+    Ok(query("INSERT INTO typeList (name) VALUES (?)")
+        .bind(&name)
+        .execute(&mut *conn)
+        .await?
+        .last_insert_rowid())
+}
+
+async fn sync_schema(conn: &mut SqliteConnection, schema: &Schema) -> Result<()> {
+    type_id(conn, &schema.name).await?;
+    let table = schema_table(&schema.name);
+    let columns = schema_columns(schema);
+    let existing = query("SELECT name, type FROM pragma_table_info(?) WHERE name != 'file_id'")
+        .bind(&table)
+        .fetch_all(&mut *conn)
+        .await?
+        .iter()
+        .map(|row| {
+            (
+                row.get::<String, &str>("name"),
+                row.get::<String, &str>("type"),
+            )
+        })
+        .collect::<Vec<_>>();
+    let wanted = columns
+        .iter()
+        .map(|(name, sql_type)| (name.to_owned(), sql_type.to_string()))
+        .collect::<Vec<_>>();
+    let is_new = query("SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?")
+        .bind(&table)
+        .fetch_optional(&mut *conn)
+        .await?
+        .is_none();
+    if is_new || existing != wanted {
+        make_schema_table(conn, schema, is_new).await?;
+    }
+    // to find and sort the files by a field, tags are a list
+    for (column, _) in columns.iter().filter(|(_, sql_type)| *sql_type != "JSON") {
+        query(&format!(
+            "CREATE INDEX IF NOT EXISTS {} ON {}({})",
+            quote(&format!("{}_{}", table, column)),
+            quote(&table),
+            quote(column)
+        ))
+        .execute(&mut *conn)
+        .await?;
+    }
+    Ok(())
+}
+
+/// the table made again with the columns of the schema, its files are put back
+async fn make_schema_table(
+    conn: &mut SqliteConnection,
+    schema: &Schema,
+    is_new: bool,
+) -> Result<()> {
+    let table = schema_table(&schema.name);
+    let columns = schema_columns(schema);
+    let ids = match is_new {
+        true => vec![],
+        false => query(&format!("SELECT file_id FROM {}", quote(&table)))
+            .fetch_all(&mut *conn)
+            .await?
+            .iter()
+            .map(|row| row.get::<i32, &str>("file_id"))
+            .collect(),
+    };
+    query(&format!("DROP TABLE IF EXISTS {}", quote(&table)))
+        .execute(&mut *conn)
+        .await?;
+    let columns = columns
+        .iter()
+        .map(|(name, sql_type)| format!(", {} {}", quote(name), sql_type))
+        .collect::<String>();
+    query(&format!(
+        "CREATE TABLE {} (file_id INTEGER PRIMARY KEY{}, FOREIGN KEY (file_id) REFERENCES files(id))",
+        quote(&table),
+        columns
+    ))
+    .execute(&mut *conn)
+    .await?;
+    for id in ids {
+        insert_schema_row(conn, schema, id).await?;
+    }
+    Ok(())
+}
+
+async fn set_schemas(conn: &mut SqliteConnection, id: i32, schemas: &[Schema]) -> Result<()> {
+    remove_schema_rows(conn, id).await?;
+    let mut last_type = 0;
+    for schema in schemas {
+        insert_schema_row(conn, schema, id).await?;
+        last_type = type_id(conn, &schema.name).await?;
+    }
+    query("UPDATE files SET type = ? WHERE id = ?")
+        .bind(last_type)
+        .bind(id)
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
+
+/// a row of the schema table from the fields in any, with the type of the column
+async fn insert_schema_row(conn: &mut SqliteConnection, schema: &Schema, id: i32) -> Result<()> {
+    let columns = schema_columns(schema);
+    let fields = fields(conn, id).await?;
+    let values = |name: &str| {
+        fields
+            .iter()
+            .filter(|(field, _)| field.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.trim())
+            .collect::<Vec<_>>()
+    };
+
+    let names = columns
+        .iter()
+        .map(|(name, _)| format!(", {}", quote(name)))
+        .collect::<String>();
+    let sql = format!(
+        "INSERT OR REPLACE INTO {} (file_id{}) VALUES (?{})",
+        quote(&schema_table(&schema.name)),
+        names,
+        ", ?".repeat(columns.len())
+    );
+    let mut insert = query(&sql).bind(id);
+    for (name, sql_type) in &columns {
+        let values = values(name);
+        insert = match *sql_type {
+            "INTEGER" => insert.bind(values.first().and_then(|v| v.parse::<i64>().ok())),
+            "REAL" => insert.bind(values.first().and_then(|v| v.parse::<f64>().ok())),
+            // a captured `a, b` is two tags too
+            "JSON" => {
+                let tags = values
+                    .iter()
+                    .flat_map(|value| value.split(','))
+                    .map(|tag| tag.trim())
+                    .filter(|tag| !tag.is_empty())
+                    .collect::<Vec<_>>();
+                insert.bind(match tags.is_empty() {
+                    true => None,
+                    false => Some(serde_json::to_string(&tags).unwrap()),
+                })
+            }
+            _ => insert.bind(values.first().map(|v| v.to_string())),
+        };
+    }
+    insert.execute(&mut *conn).await?;
+    Ok(())
+}
+
+async fn remove_schema_rows(conn: &mut SqliteConnection, id: i32) -> Result<()> {
+    let tables = query(
+        "SELECT name FROM sqlite_master WHERE type = 'table' AND name LIKE 'schema\\_%' ESCAPE '\\'",
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+    for table in tables {
+        let table = quote(&table.get::<String, &str>("name"));
+        query(&format!("DELETE FROM {} WHERE file_id = ?", table))
+            .bind(id)
+            .execute(&mut *conn)
+            .await?;
+    }
+    Ok(())
+}
+
+/// any rows of a file, then its full text search row
+async fn add_fields(
+    conn: &mut SqliteConnection,
+    id: i32,
+    fields: &[(String, String)],
+) -> Result<()> {
+    for (field, value) in fields {
+        query("INSERT INTO any (file_id, field, field_value) VALUES (?, ?, ?)")
+            .bind(id)
+            .bind(field)
+            .bind(value)
+            .execute(&mut *conn)
+            .await?;
+    }
+    refresh_search(conn, id).await
+}
+
+async fn refresh_search(conn: &mut SqliteConnection, id: i32) -> Result<()> {
+    query("DELETE FROM files_fts WHERE rowid = ?")
+        .bind(id)
+        .execute(&mut *conn)
+        .await?;
+    query(&format!(
+        "INSERT INTO files_fts(rowid,name,path,fields) {} WHERE id = ?",
+        FTS_ROW
+    ))
+    .bind(id)
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+#[async_recursion::async_recursion]
+async fn delete(conn: &mut SqliteConnection, id: i32) -> Result<()> {
+    // what point to it goes first
+    for child in children(conn, id).await?.values() {
+        delete(conn, child.id).await?;
+    }
+
+    // delete from any
+    query("DELETE FROM any WHERE file_id = ?")
+        .bind(id)
+        .execute(&mut *conn)
+        .await?;
+
+    query("DELETE FROM files_fts WHERE rowid = ?")
+        .bind(id)
+        .execute(&mut *conn)
+        .await?;
+    remove_schema_rows(conn, id).await?;
+
+    // delete from file
+    query("DELETE FROM files WHERE id = ?")
+        .bind(id)
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
+
+/// table of a schema, not quoted
+pub fn schema_table(name: &str) -> String {
+    format!("schema_{}", name.to_lowercase())
+}
+
+/// (column, sql type) of the fields of a schema, by name
+pub fn schema_columns(schema: &Schema) -> Vec<(String, &'static str)> {
+    let mut columns = schema
+        .fields
+        .values()
+        .filter(|field| !field.name().eq_ignore_ascii_case("file_id"))
+        .map(|field| {
+            let sql_type = match field.datatype() {
+                ConfigDatatype::String(_) => "TEXT",
+                ConfigDatatype::Integer(_) => "INTEGER",
+                ConfigDatatype::Float(_) => "REAL",
+                ConfigDatatype::Tags(_) => "JSON",
+            };
+            (field.name().to_lowercase(), sql_type)
+        })
+        .collect::<Vec<_>>();
+    columns.sort();
+    columns
+}
+
+/// "name" for sql, the quotes in it are doubled
+fn quote(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
 #[async_std::test]
 async fn db_test() {
     //"./testdir/fo.db"
@@ -289,9 +1468,302 @@ async fn db_test() {
 async fn children_test() {
     //"./testdir/fo.db"
     let db = IndexDB::open("./testdir").await.unwrap();
-    let data = db.children(0).await.unwrap();
+    let data = db.all_children().await.unwrap().remove(&0);
     dbg!(data);
     // db.setup().await.unwrap();
     // db.add_file(FilePath::from("./test.txt")).await.unwrap();
     // println!("{:#?}", db.search("test.txt").await.unwrap());
 }
+
+#[async_std::test]
+async fn search_test() {
+    let root = crate::helper::test_util::TestRoot::new("search");
+    let db = root.db().await;
+    let files = [
+        ("naruto-3.mp4", "3"),
+        ("naruto-4.mp4", "4"),
+        ("naruto-12.mp4", "12"),
+        ("bleach-3.mp4", "3"),
+    ];
+    for (name, epinum) in files {
+        let id = db.add_file(name, 0).await.unwrap();
+        for (field, value) in [
+            ("name", name.split('-').next().unwrap()),
+            ("epinum", epinum),
+        ] {
+            query("INSERT INTO any (file_id, field, field_value) VALUES (?, ?, ?)")
+                .bind(id)
+                .bind(field)
+                .bind(value)
+                .execute(&db.pool)
+                .await
+                .unwrap();
+        }
+        db.refresh_search(id).await.unwrap();
+    }
+    let mut sl = SchemaList::new();
+    sl.parse_format("anime".to_owned(), "name epinum(num) | |");
+
+    let search = |q: &str| Operation::parse(q).unwrap();
+    let found = |q: &str| {
+        let (db, sl) = (&db, &sl);
+        let operation = search(q);
+        async move {
+            let hits = db.search(&operation, sl, false).await.unwrap();
+            hits.into_iter().map(|hit| hit.path).collect::<Vec<_>>()
+        }
+    };
+    assert_eq!(
+        found("name: naruto epinum: 3 | 4").await,
+        vec!["naruto-3.mp4", "naruto-4.mp4"]
+    );
+    assert_eq!(found("-naruto epinum: 3").await, vec!["bleach-3.mp4"]);
+    assert_eq!(
+        found("mp4 (bleach | epinum: 4)").await,
+        vec!["bleach-3.mp4", "naruto-4.mp4"]
+    );
+    assert!(found("name: _").await.is_empty());
+
+    // as numbers, not text
+    assert_eq!(
+        found("epinum: 4..12").await,
+        vec!["naruto-12.mp4", "naruto-4.mp4"]
+    );
+    assert_eq!(found("epinum: >4").await, vec!["naruto-12.mp4"]);
+    assert_eq!(found("naruto epinum: <=4").await.len(), 2);
+    assert!(db.search(&search("epinum: >x"), &sl, false).await.is_err());
+
+    // the files were just added
+    assert_eq!(found("modified: <1d").await.len(), 4);
+    assert!(found("modified: >1d").await.is_empty());
+    assert_eq!(found("modified: 2000-01-01..").await.len(), 4);
+    assert!(found("modified: ..2000-01-01").await.is_empty());
+
+    // full text, by prefix and split on the separators
+    db.index_path(&PathBuf::from("./naruto_movie/[sub] extra.mkv"))
+        .await
+        .unwrap();
+    db.add_file("Naruto_Shippuden.mkv", 0).await.unwrap();
+    assert_eq!(found("naru mkv").await[0], "Naruto_Shippuden.mkv");
+    assert_eq!(
+        found("naru mkv").await[1..],
+        ["./naruto_movie/[sub] extra.mkv"]
+    );
+    assert_eq!(found("sub").await, ["./naruto_movie/[sub] extra.mkv"]);
+    assert_eq!(found("shipp").await, ["Naruto_Shippuden.mkv"]);
+
+    // typos, unless exact
+    assert_eq!(found("naurto shipuden").await, ["Naruto_Shippuden.mkv"]);
+    // a name match before a path one
+    let naurto = found("naurto").await;
+    assert_eq!(naurto.len(), 6);
+    assert_eq!(naurto[5], "./naruto_movie/[sub] extra.mkv");
+    for exact in ["naurto", "uden"] {
+        assert!(db
+            .search(&search(exact), &sl, true)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+    assert_eq!(found("-naurto mp4").await, ["bleach-3.mp4"]);
+    // but not in the first letter
+    assert!(found("maruto").await.is_empty());
+    // the index has no diacritics, the query neither
+    db.add_file("Éclair_Ärger.mkv", 0).await.unwrap();
+    assert_eq!(found("éclaire ärgr").await, ["Éclair_Ärger.mkv"]);
+    assert_eq!(found("Eclair").await, ["Éclair_Ärger.mkv"]);
+
+    // facets of what was found
+    for (path, tags) in [
+        ("naruto-3.mp4", "action, comedy"),
+        ("bleach-3.mp4", "action"),
+    ] {
+        query("INSERT INTO any (file_id, field, field_value) SELECT id, 'tags', ? FROM files WHERE path = ?")
+            .bind(tags)
+            .bind(path)
+            .execute(&db.pool)
+            .await
+            .unwrap();
+    }
+    let hits = db.search(&search("epinum: 3"), &sl, true).await.unwrap();
+    let ids = hits.iter().map(|hit| hit.id).collect::<Vec<_>>();
+    let facets = db.facets(&ids, &["tags", "name", "ext"]).await.unwrap();
+    let counts = |list: &[(&str, usize)]| {
+        list.iter()
+            .map(|(value, count)| (value.to_string(), *count))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(facets[0].counts, counts(&[("action", 2), ("comedy", 1)]));
+    assert_eq!(facets[1].counts, counts(&[("bleach", 1), ("naruto", 1)]));
+    assert_eq!(facets[2].counts, counts(&[("mp4", 2)]));
+}
+
+#[async_std::test]
+async fn schema_table_test() {
+    let root = crate::helper::test_util::TestRoot::new("schema_table");
+    let db = root.db().await;
+    let mut sl = SchemaList::new();
+    sl.parse_format(
+        "anime".to_owned(),
+        "name epinum(num) genre(str[]) rating(flo)||",
+    );
+    let anime = sl.get("anime").unwrap().clone();
+
+    let id = db.add_file("naruto-02.mkv", 0).await.unwrap();
+    let fields = [
+        ("name", "naruto"),
+        ("epinum", "02"),
+        ("genre", "action, comedy"),
+        ("genre", "drama"),
+        ("rating", "no"),
+    ]
+    .map(|(field, value)| (field.to_owned(), value.to_owned()));
+    db.sync_schema(&anime).await.unwrap();
+    let mut batch = db.batch().await.unwrap();
+    batch.set_fields(id, &fields).await.unwrap();
+    batch.set_schemas(id, &[anime]).await.unwrap();
+    batch.commit().await.unwrap();
+
+    let row = query(
+        "SELECT typeof(epinum) AS ty, epinum, genre, rating, typeList.name AS type FROM schema_anime \
+        JOIN files ON files.id = file_id JOIN typeList ON typeList.id = files.type",
+    )
+    .fetch_one(&db.pool)
+    .await
+    .unwrap();
+    assert_eq!(row.get::<String, &str>("ty"), "integer");
+    assert_eq!(row.get::<i64, &str>("epinum"), 2);
+    assert_eq!(
+        row.get::<String, &str>("genre"),
+        r#"["action","comedy","drama"]"#
+    );
+    assert_eq!(row.get::<Option<f64>, &str>("rating"), None);
+    assert_eq!(row.get::<String, &str>("type"), "anime");
+    let indexes =
+        query("SELECT name FROM sqlite_master WHERE type = 'index' AND tbl_name = 'schema_anime'")
+            .fetch_all(&db.pool)
+            .await
+            .unwrap()
+            .iter()
+            .map(|row| row.get::<String, &str>("name"))
+            .collect::<HashSet<_>>();
+    let expected = [
+        "schema_anime_epinum",
+        "schema_anime_name",
+        "schema_anime_rating",
+    ];
+    assert_eq!(indexes, expected.map(|name| name.to_owned()).into());
+
+    // a new field make the table again with the same files
+    sl.parse_format("anime".to_owned(), "name epinum(num) season(num)||");
+    db.sync_schemas(&sl).await.unwrap();
+    let row = query("SELECT file_id, season FROM schema_anime")
+        .fetch_one(&db.pool)
+        .await
+        .unwrap();
+    assert_eq!(row.get::<i32, &str>("file_id"), id);
+    assert_eq!(row.get::<Option<i64>, &str>("season"), None);
+    let genre = query("SELECT 1 FROM pragma_table_info('schema_anime') WHERE name = 'genre'")
+        .fetch_optional(&db.pool)
+        .await
+        .unwrap();
+    assert!(genre.is_none());
+
+    db.delete(id).await.unwrap();
+    let count = query("SELECT COUNT(*) AS count FROM schema_anime")
+        .fetch_one(&db.pool)
+        .await
+        .unwrap();
+    assert_eq!(count.get::<i32, &str>("count"), 0);
+}
+
+#[async_std::test]
+async fn migrate_test() {
+    let root = crate::helper::test_util::TestRoot::new("migrate");
+    std::fs::create_dir_all(root.join("new")).unwrap();
+    let new = IndexDB::open(root.join("new")).await.unwrap();
+    assert_eq!(new.version().await.unwrap(), MIGRATIONS.len());
+    assert!(!root.join("new/fo.db.v0.bak").exists());
+
+    // a fo.db made before the migrations, without placement, any.file_id and the rest
+    let old = IndexDB::connect(&root).await.unwrap();
+    for sql in [
+        "CREATE TABLE typeList (id INTEGER PRIMARY KEY AUTOINCREMENT, name TEXT NOT NULL)",
+        "CREATE TABLE files (id INTEGER PRIMARY KEY AUTOINCREMENT, name TEXT NOT NULL, \
+        path TEXT NOT NULL, last_mod TEXT NOT NULL, is_folder INTEGER NOT NULL DEFAULT 0, \
+        type INTEGER DEFAULT 0, parent INTEGER)",
+        "CREATE TABLE any (id INTEGER PRIMARY KEY AUTOINCREMENT, field TEXT NOT NULL, field_value TEXT NOT NULL)",
+        "INSERT INTO files (id,name,path,last_mod,is_folder) VALUES (0,'root','/',datetime('now'),1)",
+        "INSERT INTO files (name,path,last_mod,parent) VALUES ('naruto.mkv','./naruto.mkv',datetime('now'),0)",
+    ] {
+        query(sql).execute(&old.pool).await.unwrap();
+    }
+    assert_eq!(old.pending().await.unwrap().len(), MIGRATIONS.len());
+    old.pool.close().await;
+
+    let db = root.db().await;
+    assert_eq!(db.version().await.unwrap(), MIGRATIONS.len());
+    assert!(db.pending().await.unwrap().is_empty());
+    assert!(root.join("fo.db.v0.bak").exists());
+    // the files are still there, and found by the full text search
+    let operation = Operation::parse("naruto").unwrap();
+    let hits = db
+        .search(&operation, &SchemaList::new(), true)
+        .await
+        .unwrap();
+    assert_eq!(hits.len(), 1);
+    db.set_placement(hits[0].id, "move").await.unwrap();
+
+    // nothing to do the next time
+    assert!(db.migrate().await.unwrap().is_empty());
+    assert!(IndexDB::pending_in(&root).await.unwrap().is_empty());
+    // a check doesn't make one
+    let none = root.join("none");
+    std::fs::create_dir_all(&none).unwrap();
+    assert!(IndexDB::pending_in(&none).await.unwrap().is_empty());
+    assert!(!none.join("fo.db").exists());
+    assert!(!root
+        .join(format!("fo.db.v{}.bak", MIGRATIONS.len()))
+        .exists());
+}
+
+#[async_std::test]
+async fn hash_test() {
+    use crate::helper::HASH_CHUNK;
+
+    let root = crate::helper::test_util::TestRoot::new("hash");
+    std::fs::write(root.join("small.txt"), "small").unwrap();
+    std::fs::write(root.join("big.bin"), vec![1; 3 * HASH_CHUNK as usize]).unwrap();
+    let db = root.db().await;
+    let small = db.add_file("./small.txt", 0).await.unwrap();
+    let big = db.add_file("./big.bin", 0).await.unwrap();
+
+    let hash = db.content_hash(small, false).await.unwrap().unwrap();
+    assert_eq!(hash.size, 5);
+    assert_eq!(hash.full, None);
+    let hash = db.content_hash(small, true).await.unwrap().unwrap();
+    assert_eq!(hash.full, Some(hash.partial.to_owned()));
+
+    // the full hash is kept, not made again
+    let md5 = FileHelper::new(root.join("big.bin")).md5().unwrap();
+    let hash = db.content_hash(big, true).await.unwrap().unwrap();
+    assert_eq!(hash.full.as_ref(), Some(&md5));
+    assert_ne!(hash.partial, md5);
+    std::fs::remove_file(root.join("big.bin")).unwrap();
+    assert_eq!(db.content_hash(big, true).await.unwrap().unwrap(), hash);
+    assert_eq!(db.content_hash(0, true).await.unwrap(), None);
+}
+
+#[async_std::test]
+async fn root_spelling_test() {
+    let root = crate::helper::test_util::TestRoot::new("spelling");
+    std::os::unix::fs::symlink(&root, root.join("link")).unwrap();
+    let db = IndexDB::open(root.join("link")).await.unwrap();
+    assert_eq!(db.get_path_new(), std::fs::canonicalize(&root).unwrap());
+    // the destination of a move isn't there yet
+    assert_eq!(
+        db.relative_path(root.join("link/new/naruto.mkv")),
+        "./new/naruto.mkv"
+    );
+    assert_eq!(db.relative_path(root.join("naruto.mkv")), "./naruto.mkv");
+}
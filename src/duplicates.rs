@@ -0,0 +1,293 @@
+// Duplicates
+// files with the same content, found with the hashes in the index. for each
+// group one copy is kept and the others are removed or made hard links to it,
+// through the journal so `undo` can bring them back.
+// the one kept is the newest, or the one already where organize would put it.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Utc};
+
+use crate::{
+    config_reader::Config,
+    db::{FileIdentity, IndexDB},
+    error::FOError,
+    helper::FileHelper,
+    journal::Journal,
+    mover::Mover,
+    organizer::{same_content, MoveOutcome, Resolution},
+    schema::SchemaList,
+};
+
+/// Which copy of a group stays
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Keep {
+    /// the one modified last
+    Newest,
+    /// the one where organize would put it, groups without one are skipped
+    Organized,
+}
+
+/// A copy with what the index know about it
+#[derive(Debug)]
+pub struct Duplicate {
+    pub identity: FileIdentity,
+    pub path: PathBuf,
+    pub modified: DateTime<Utc>,
+    /// last schema it went through
+    pub schema: String,
+    pub fields: Vec<(String, String)>,
+    /// already where organize would put it
+    pub organized: bool,
+}
+
+#[derive(Debug)]
+pub struct DuplicateGroup {
+    pub size: i64,
+    pub copies: Vec<Duplicate>,
+}
+
+impl DuplicateGroup {
+    /// index of the copy to keep, None if there is no such copy
+    pub fn keeper(&self, keep: Keep) -> Option<usize> {
+        match keep {
+            Keep::Newest => self
+                .copies
+                .iter()
+                .enumerate()
+                .max_by(|(_, a), (_, b)| a.modified.cmp(&b.modified).then(b.path.cmp(&a.path)))
+                .map(|(i, _)| i),
+            Keep::Organized => self.copies.iter().position(|copy| copy.organized),
+        }
+    }
+}
+
+/// Groups of copies in the index, the biggest files first. config and the
+/// schemas tell where organize would put each copy
+pub async fn find(
+    db: &IndexDB,
+    config: &Config,
+    schemalist: &SchemaList,
+) -> Result<Vec<DuplicateGroup>, FOError> {
+    let mut groups = vec![];
+    for group in db.duplicates().await? {
+        let mut copies = vec![];
+        for identity in group {
+            let path = db.absolute(&identity.path);
+            let file_helper = FileHelper::new(&path);
+            // config files are not media, and a missing one is not a copy anymore
+            let Ok(state) = file_helper.state() else {
+                continue;
+            };
+            if file_helper.is_config() {
+                continue;
+            }
+            let organized = Mover::new(&path)
+                .with_meta(file_helper.read_meta()?)
+                .get_path(config, schemalist)
+                .is_ok_and(|organized| same_file(&db.absolute(&organized), &path));
+            copies.push(Duplicate {
+                schema: db.file_type(identity.id).await?,
+                fields: db.fields(identity.id).await?,
+                identity,
+                path,
+                modified: state.modified,
+                organized,
+            });
+        }
+        if copies.len() > 1 {
+            groups.push(DuplicateGroup {
+                size: copies[0].identity.size.unwrap_or_default(),
+                copies,
+            });
+        }
+    }
+    Ok(groups)
+}
+
+/// Remove the copies of the group but the one to keep, or make them hard links
+/// to it. nothing is touched without a journal
+pub async fn resolve(
+    group: &DuplicateGroup,
+    keep: Keep,
+    hardlink: bool,
+    journal: Option<&Journal<'_>>,
+) -> Vec<(PathBuf, MoveOutcome)> {
+    let Some(kept) = group.keeper(keep) else {
+        let reason = "no copy is where organize would put it".to_owned();
+        return group
+            .copies
+            .iter()
+            .map(|copy| (copy.path.clone(), MoveOutcome::Skipped(reason.clone())))
+            .collect();
+    };
+    let kept = &group.copies[kept];
+
+    let mut result = vec![];
+    for copy in &group.copies {
+        if copy.path == kept.path {
+            continue;
+        }
+        let outcome = match resolve_copy(copy, kept, hardlink, journal).await {
+            Ok(outcome) => outcome,
+            Err(e) => MoveOutcome::Failed(e),
+        };
+        result.push((copy.path.clone(), outcome));
+    }
+    result
+}
+
+async fn resolve_copy(
+    copy: &Duplicate,
+    kept: &Duplicate,
+    hardlink: bool,
+    journal: Option<&Journal<'_>>,
+) -> Result<MoveOutcome, FOError> {
+    if same_file(&copy.path, &kept.path) {
+        return Ok(MoveOutcome::Skipped("already the same file".to_owned()));
+    }
+    // the index may be older than the files
+    if !same_content(&copy.path, &kept.path)? {
+        return Ok(MoveOutcome::Skipped("not identical anymore".to_owned()));
+    }
+    let Some(journal) = journal else {
+        return Ok(MoveOutcome::Planned);
+    };
+
+    match hardlink {
+        true => {
+            // made next to it then renamed over it, the copy is never missing
+            let name = copy.path.file_name().unwrap_or_default().to_string_lossy();
+            let temp = copy.path.with_file_name(format!(".{}.fo-link", name));
+            fs::hard_link(&kept.path, &temp)?;
+            if let Err(e) = fs::rename(&temp, &copy.path) {
+                let _ = fs::remove_file(&temp);
+                return Err(e.into());
+            }
+            journal
+                .record_duplicate(&copy.path, &kept.path, Resolution::Link)
+                .await?;
+            Ok(MoveOutcome::Linked)
+        }
+        false => {
+            fs::remove_file(&copy.path)?;
+            journal
+                .record_duplicate(&copy.path, &kept.path, Resolution::Dedupe)
+                .await?;
+            Ok(MoveOutcome::Removed)
+        }
+    }
+}
+
+/// the same file on the disk, a link or a hard link of the other
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (FileHelper::new(a).inode(), FileHelper::new(b).inode()) {
+        (Some(a), Some(b)) => a == b,
+        _ => matches!((fs::canonicalize(a), fs::canonicalize(b)), (Ok(a), Ok(b)) if a == b),
+    }
+}
+
+#[async_std::test]
+async fn test_duplicates() {
+    use crate::{indexer::Indexer, journal::undo};
+
+    let root = crate::helper::test_util::TestRoot::new("duplicates");
+    fs::create_dir_all(root.join("naruto")).unwrap();
+    fs::write(
+        root.join("_data.yaml"),
+        r#"
+        _meta:
+            children: anime
+        _schema:
+            anime:
+                fields: name, epinum(num)
+                filename: '%name%/%name%-%epinum%.mkv'
+        _import:
+            - "{?}-{?}.{?}": name, epinum, ext
+        "#,
+    )
+    .unwrap();
+    let content = "episode 1".repeat(10000);
+    for name in [
+        "naruto/naruto-1.mkv",
+        "naruto-1.mkv",
+        "copy of naruto-1.mkv",
+    ] {
+        fs::write(root.join(name), &content).unwrap();
+    }
+    fs::write(root.join("other.mkv"), "episode 2".repeat(10000)).unwrap();
+
+    let config = FileHelper::new(&root).read_config().unwrap();
+    let sl = SchemaList::from(&config.schema);
+    let mut db = root.db().await;
+    Indexer::open(&mut db).indexing("./", 0).await.unwrap();
+    let groups = find(&db, &config, &sl).await.unwrap();
+    assert_eq!(groups.len(), 1);
+    let group = &groups[0];
+    assert_eq!(group.size, content.len() as i64);
+    assert_eq!(group.copies.len(), 3);
+    let organized = group.keeper(Keep::Organized).unwrap();
+    assert!(group.copies[organized]
+        .path
+        .ends_with("naruto/naruto-1.mkv"));
+    let naruto = group
+        .copies
+        .iter()
+        .find(|copy| copy.path == root.join("naruto-1.mkv"))
+        .unwrap();
+    assert_eq!(naruto.schema, "anime");
+    assert!(naruto
+        .fields
+        .contains(&("name".to_owned(), "naruto".to_owned())));
+
+    // nothing is touched without a journal
+    let planned = resolve(group, Keep::Organized, true, None).await;
+    assert_eq!(planned.len(), 2);
+    assert!(planned
+        .iter()
+        .all(|(_, outcome)| matches!(outcome, MoveOutcome::Planned)));
+    assert!(!same_file(
+        &root.join("naruto-1.mkv"),
+        &root.join("naruto/naruto-1.mkv")
+    ));
+
+    let journal = Journal::start(&db).await.unwrap();
+    let linked = resolve(group, Keep::Organized, true, Some(&journal)).await;
+    assert!(linked
+        .iter()
+        .all(|(_, outcome)| matches!(outcome, MoveOutcome::Linked)));
+    assert!(same_file(
+        &root.join("naruto-1.mkv"),
+        &root.join("naruto/naruto-1.mkv")
+    ));
+    assert_eq!(
+        fs::read_to_string(root.join("naruto-1.mkv")).unwrap(),
+        content
+    );
+
+    // copies of their own again
+    undo(&db, journal.run_id).await.unwrap();
+    assert!(!same_file(
+        &root.join("naruto-1.mkv"),
+        &root.join("naruto/naruto-1.mkv")
+    ));
+    assert_eq!(
+        fs::read_to_string(root.join("naruto-1.mkv")).unwrap(),
+        content
+    );
+
+    let journal = Journal::start(&db).await.unwrap();
+    resolve(group, Keep::Newest, false, Some(&journal)).await;
+    let left = [
+        "naruto/naruto-1.mkv",
+        "naruto-1.mkv",
+        "copy of naruto-1.mkv",
+    ]
+    .iter()
+    .filter(|name| root.join(name).exists())
+    .count();
+    assert_eq!(left, 1);
+}
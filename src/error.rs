@@ -14,6 +14,14 @@ pub enum FOError {
     RegexError(#[from] regex::Error),
     #[error("Schema error: {0}")]
     SchemaError(String),
+    #[error("Move error: {0}")]
+    MoveError(String),
+    #[error("Query error: {0}")]
+    QueryError(String),
+    #[error("JSON error: {0}")]
+    JsonError(#[from] serde_json::Error),
+    #[error("Database error: {0}")]
+    DatabaseError(#[from] sqlx::Error),
 }
 
 // #[derive(Debug)]
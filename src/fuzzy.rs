@@ -0,0 +1,199 @@
+// Fuzzy matching
+// text is compared word by word after case and diacritic folding, a query
+// word match a text word when it's the same, a prefix, inside it, a typo away
+// (edit distance with swapped letters counted as one) or its letters are in
+// order.
+// the score is between 0 and 1, the closer the better.
+
+use crate::{config_reader::Notation, helper::match_text};
+
+/// lowercase without the diacritics, like the unicode61 tokenizer of the
+/// search index, with the few letters that fold to something else
+pub fn fold(text: &str) -> String {
+    text.to_lowercase()
+        .chars()
+        .map(base_letter)
+        .collect::<String>()
+        .replace('ß', "ss")
+        .replace('ς', "σ")
+}
+
+/// the letter without its accent, for the latin ones
+fn base_letter(c: char) -> char {
+    const ACCENTED: [(char, &str); 18] = [
+        ('a', "àáâãäåāăą"),
+        ('c', "çćĉċč"),
+        ('d', "ď"),
+        ('e', "èéêëēĕėęě"),
+        ('g', "ĝğġģ"),
+        ('h', "ĥ"),
+        ('i', "ìíîïĩīĭįı"),
+        ('j', "ĵ"),
+        ('k', "ķ"),
+        ('l', "ĺļľ"),
+        ('n', "ñńņňŉ"),
+        ('o', "òóôõöōŏő"),
+        ('r', "ŕŗř"),
+        ('s', "śŝşš"),
+        ('t', "ţť"),
+        ('u', "ùúûüũūŭůűų"),
+        ('y', "ýÿŷ"),
+        ('z', "źżž"),
+    ];
+    if c.is_ascii() {
+        return c;
+    }
+    ACCENTED
+        .iter()
+        .find(|(_, accented)| accented.contains(c))
+        .map_or(c, |(base, _)| *base)
+}
+
+/// folded words, split on everything that is not a letter or a number
+pub fn words(text: &str) -> Vec<String> {
+    fold(text)
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_owned())
+        .collect()
+}
+
+/// every word of the query should match a word of the text, None if one doesn't
+pub fn score(query: &str, text: &str) -> Option<f64> {
+    let query = words(query);
+    if query.is_empty() {
+        return None;
+    }
+    let text = words(text);
+    let mut total = 0.0;
+    for query_word in &query {
+        total += text
+            .iter()
+            .filter_map(|text_word| word_score(query_word, text_word))
+            .fold(None, |best: Option<f64>, score| {
+                Some(best.map_or(score, |best| best.max(score)))
+            })?;
+    }
+    Some(total / query.len() as f64)
+}
+
+/// both are folded already
+pub fn word_score(query: &str, text: &str) -> Option<f64> {
+    if query == text {
+        return Some(1.0);
+    }
+    if text.starts_with(query) {
+        return Some(0.9);
+    }
+    if text.contains(query) {
+        return Some(0.8);
+    }
+
+    let query = query.chars().collect::<Vec<_>>();
+    let text = text.chars().collect::<Vec<_>>();
+    // a typo in the whole word or in the start of it
+    let prefix = &text[..text.len().min(query.len())];
+    let distance = edit_distance(&query, &text).min(edit_distance(&query, prefix));
+    if distance <= typos(query.len()) {
+        return Some(0.7 - 0.1 * distance as f64);
+    }
+    if query.len() >= 3 && is_subsequence(&query, &text) {
+        return Some(0.4 * query.len() as f64 / text.len() as f64);
+    }
+    None
+}
+
+/// typos allowed in a word this long
+fn typos(len: usize) -> usize {
+    match len {
+        0..=3 => 0,
+        4..=6 => 1,
+        _ => 2,
+    }
+}
+
+/// insert, delete, replace or swap two letters next to each other
+pub fn edit_distance(a: &[char], b: &[char]) -> usize {
+    // rows of the table for i - 2, i - 1 and i
+    let mut before = vec![0; b.len() + 1];
+    let mut last = (0..=b.len()).collect::<Vec<_>>();
+    for i in 1..=a.len() {
+        let mut current = vec![i; b.len() + 1];
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            current[j] = (last[j] + 1)
+                .min(current[j - 1] + 1)
+                .min(last[j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                current[j] = current[j].min(before[j - 2] + 1);
+            }
+        }
+        before = std::mem::replace(&mut last, current);
+    }
+    last[b.len()]
+}
+
+fn is_subsequence(query: &[char], text: &[char]) -> bool {
+    let mut text = text.iter();
+    query.iter().all(|c| text.any(|t| t == c))
+}
+
+/// `anim.name` for the schema `anime`, see Notation for how
+pub fn notation_match(notation: &str, schema_name: &str, mode: Notation) -> bool {
+    if mode == Notation::Prefix {
+        return match_text(notation, schema_name);
+    }
+    let (notation, schema_name) = (fold(notation), fold(schema_name));
+    if notation.is_empty() || notation == schema_name {
+        return true;
+    }
+    if mode == Notation::Strict {
+        return false;
+    }
+    if notation.starts_with(&schema_name) || schema_name.starts_with(&notation) {
+        return true;
+    }
+    let notation = notation.chars().collect::<Vec<_>>();
+    let schema_name = schema_name.chars().collect::<Vec<_>>();
+    edit_distance(&notation, &schema_name) <= typos(notation.len()).min(1)
+}
+
+#[test]
+fn test_edit_distance() {
+    let distance = |a: &str, b: &str| {
+        edit_distance(
+            &a.chars().collect::<Vec<_>>(),
+            &b.chars().collect::<Vec<_>>(),
+        )
+    };
+    assert_eq!(distance("naurto", "naruto"), 1);
+    assert_eq!(distance("narto", "naruto"), 1);
+    assert_eq!(distance("kitten", "sitting"), 3);
+    assert_eq!(distance("", "abc"), 3);
+    assert_eq!(distance("abc", "abc"), 0);
+}
+
+#[test]
+fn test_score() {
+    let name = "Naruto_Shippuden_[01].mkv";
+    assert_eq!(score("naruto", name), Some(1.0));
+    assert!(score("naurto", name).unwrap() > score("nrt", name).unwrap());
+    assert!(score("naru", name).unwrap() > score("naurto", name).unwrap());
+    assert!(score("NARUTO SHIPUDEN", name).is_some());
+    assert!(score("naruto bleach", name).is_none());
+    assert!(score("xy", name).is_none());
+    // case folding
+    assert_eq!(score("STRASSE", "straße"), Some(1.0));
+    assert_eq!(score("ΟΔΟΣ", "οδος"), Some(1.0));
+}
+
+#[test]
+fn test_notation_match() {
+    assert!(notation_match("anim", "Anime", Notation::Prefix));
+    assert!(!notation_match("anmie", "anime", Notation::Prefix));
+    assert!(notation_match("anmie", "anime", Notation::Fuzzy));
+    assert!(!notation_match("book", "anime", Notation::Fuzzy));
+    assert!(notation_match("", "anime", Notation::Strict));
+    assert!(!notation_match("anim", "anime", Notation::Strict));
+    assert!(notation_match("ANIME", "anime", Notation::Strict));
+}
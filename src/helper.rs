@@ -3,16 +3,25 @@ use std::{
     collections::HashMap,
     ffi::OsStr,
     fs,
+    io::{Read, Seek, SeekFrom},
     path::{Path, PathBuf},
+    time::Duration,
 };
 
+use base64ct::{Base64, Encoding};
 use chrono::{DateTime, Utc};
+use md5::{Digest, Md5};
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    config_reader::{Config, SchemaConfig},
+    config_reader::{Config, FileMeta, MetaConfig, Notation, SchemaConfig},
     error::FOError,
+    fuzzy::notation_match,
 };
 
+/// config files in a folder, for the folder and everything in it
+pub const FOLDER_CONFIG: [&str; 3] = ["_data.yaml", "_schema.yaml", "_meta.yaml"];
+
 pub fn cut_path<P: AsRef<Path>, Q: AsRef<Path>>(full: P, cut: Q) -> PathBuf {
     let cut_path = PathBuf::from(cut.as_ref());
     let full_path = PathBuf::from(full.as_ref());
@@ -31,6 +40,22 @@ pub fn cut_path<P: AsRef<Path>, Q: AsRef<Path>>(full: P, cut: Q) -> PathBuf {
     }
     PathBuf::from(format!("./{}", cutted.join("/")))
 }
+/// path of `path` seen from the `base` folder, both should be absolute
+pub fn relative_to<P: AsRef<Path>, Q: AsRef<Path>>(path: P, base: Q) -> PathBuf {
+    let path = path.as_ref().components().collect::<Vec<_>>();
+    let base = base.as_ref().components().collect::<Vec<_>>();
+    let common = path.iter().zip(&base).take_while(|(a, b)| a == b).count();
+
+    let mut result = PathBuf::new();
+    for _ in common..base.len() {
+        result.push("..");
+    }
+    for component in &path[common..] {
+        result.push(component);
+    }
+    result
+}
+
 pub trait PathHelper {
     fn to_string(&self) -> String;
     fn format(&self) -> String;
@@ -80,10 +105,20 @@ macro_rules! string_helper {
 path_helper!(Path, PathBuf, &Path, &PathBuf);
 string_helper!(OsStr, &OsStr);
 
+/// bytes read from each end of a file by partial_md5
+pub const HASH_CHUNK: u64 = 64 * 1024;
+
 pub struct FileHelper {
     path: PathBuf,
 }
 
+/// Size and modified time of a file, to know if it was changed
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileState {
+    pub size: i64,
+    pub modified: DateTime<Utc>,
+}
+
 impl FileHelper {
     pub fn new<P: AsRef<Path>>(path: P) -> Self {
         Self {
@@ -105,17 +140,94 @@ impl FileHelper {
             Some(date)
         };
 
-        match (file_meta, yaml_meta) {
+        let last_mod = match (file_meta, yaml_meta) {
             (Some(d1), Some(d2)) => Some(max(get_dt(d1)?, get_dt(d2)?)),
             (Some(d), None) | (None, Some(d)) => Some(get_dt(d)?),
             _ => None,
-        }
+        };
+        // a new meta.yaml change the fields too, the config in a folder the
+        // fields of everything in it
+        let configs = match self.path.is_dir() {
+            true => FOLDER_CONFIG
+                .iter()
+                .map(|name| self.path.join(name))
+                .collect(),
+            false => vec![self.meta_path()],
+        };
+        configs
+            .iter()
+            .filter_map(|config| fs::metadata(config).ok().and_then(get_dt))
+            .fold(last_mod, |last_mod, config| {
+                Some(last_mod.map_or(config, |d| max(d, config)))
+            })
     }
 
     pub fn get_path(&self) -> &Path {
         &self.path
     }
 
+    /// unlike last_mod, this one doesn't care about the yaml
+    pub fn state(&self) -> Result<FileState, FOError> {
+        let meta = fs::metadata(&self.path)?;
+        Ok(FileState {
+            size: meta.len() as i64,
+            modified: DateTime::from(meta.modified()?),
+        })
+    }
+
+    /// (device, inode), to know the file after a move. None if there is no such thing
+    pub fn inode(&self) -> Option<(i64, i64)> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let meta = fs::metadata(&self.path).ok()?;
+            Some((meta.dev() as i64, meta.ino() as i64))
+        }
+        #[cfg(not(unix))]
+        None
+    }
+
+    /// md5 of the content, base64 encoded
+    pub fn md5(&self) -> Result<String, FOError> {
+        let mut file = fs::File::open(&self.path)?;
+        let mut hasher = Md5::new();
+        std::io::copy(&mut file, &mut hasher)?;
+        Ok(Base64::encode_string(&hasher.finalize()))
+    }
+
+    /// md5 of the size with the first and the last chunk, the same as md5
+    /// for a file of two chunks or less. to tell files apart without reading them
+    pub fn partial_md5(&self) -> Result<String, FOError> {
+        let mut file = fs::File::open(&self.path)?;
+        let size = file.metadata()?.len();
+        if size <= 2 * HASH_CHUNK {
+            return self.md5();
+        }
+        let mut hasher = Md5::new();
+        hasher.update(size.to_le_bytes());
+        let mut chunk = vec![0; HASH_CHUNK as usize];
+        file.read_exact(&mut chunk)?;
+        hasher.update(&chunk);
+        file.seek(SeekFrom::End(-(HASH_CHUNK as i64)))?;
+        file.read_exact(&mut chunk)?;
+        hasher.update(&chunk);
+        Ok(Base64::encode_string(&hasher.finalize()))
+    }
+
+    /// unfinished download, checked by extension
+    pub fn is_temp(&self, extensions: &[String]) -> bool {
+        match self.path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) => extensions.iter().any(|e| e.eq_ignore_ascii_case(ext)),
+            None => false,
+        }
+    }
+
+    /// config yaml and the index database (with sqlite temp files), these are never organized
+    pub fn is_config(&self) -> bool {
+        let is_yaml = self.path.extension().is_some_and(|ext| ext.eq("yaml"));
+        is_yaml || self.file_name().starts_with("fo.db")
+    }
+
     pub fn read_config(&self) -> Result<Config, FOError> {
         let mut config: Config = Default::default();
 
@@ -139,13 +251,59 @@ impl FileHelper {
         )?;
 
         // yaml inside
+        if !self.path.is_dir() {
+            return Ok(config);
+        }
         read_file_to_combine_config(&mut config, &self.path.join("_data.yaml"), |yaml| {
             serde_yaml::from_str::<Config>(yaml).map_err(|e| e.into())
         })?;
+        read_file_to_combine_config(&mut config, &self.path.join("_schema.yaml"), |yaml| {
+            let schema = serde_yaml::from_str::<SchemaConfig>(yaml)?;
+            Ok(Config {
+                schema,
+                ..Default::default()
+            })
+        })?;
+        read_file_to_combine_config(&mut config, &self.path.join("_meta.yaml"), |yaml| {
+            let meta = serde_yaml::from_str::<MetaConfig>(yaml)?;
+            Ok(Config {
+                meta,
+                ..Default::default()
+            })
+        })?;
 
         Ok(config)
     }
 
+    /// `<file>.meta.yaml`, the choices remembered for the file
+    pub fn meta_path(&self) -> PathBuf {
+        let mut name = self.path.file_name().unwrap_or_default().to_owned();
+        name.push(".meta.yaml");
+        self.path.with_file_name(name)
+    }
+
+    /// default if the file has no meta.yaml
+    pub fn read_meta(&self) -> Result<FileMeta, FOError> {
+        match fs::read_to_string(self.meta_path()) {
+            Ok(yaml) => Ok(serde_yaml::from_str(&yaml)?),
+            Err(e) if matches!(e.kind(), std::io::ErrorKind::NotFound) => Ok(FileMeta::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// an empty meta remove the meta.yaml
+    pub fn write_meta(&self, meta: &FileMeta) -> Result<(), FOError> {
+        let path = self.meta_path();
+        if meta == &FileMeta::default() {
+            if path.exists() {
+                fs::remove_file(path)?;
+            }
+            return Ok(());
+        }
+        fs::write(path, serde_yaml::to_string(meta)?)?;
+        Ok(())
+    }
+
     pub fn read_dir(&self) -> Result<Vec<FileHelper>, FOError> {
         let mut files = vec![];
         for entry in fs::read_dir(&self.path)? {
@@ -160,6 +318,58 @@ impl FileHelper {
     }
 }
 
+/// Split files into (stable, unstable). A file modified within the settle
+/// period is stable when its size and mtime stay the same for the whole settle
+/// period, the older ones are stable already. Nothing is waited for without such a file
+pub async fn split_stable(
+    files: Vec<FileHelper>,
+    settle: Duration,
+) -> (Vec<FileHelper>, Vec<FileHelper>) {
+    if settle.is_zero() || files.is_empty() {
+        return (files, vec![]);
+    }
+    let before = snapshot(&files, settle);
+    if before.iter().flatten().any(|(_, recent)| *recent) {
+        async_std::task::sleep(settle).await;
+    }
+    split_changed(files, before)
+}
+
+/// state of the files, and if they were modified within the settle period
+fn snapshot(files: &[FileHelper], settle: Duration) -> Vec<Option<(FileState, bool)>> {
+    let now = Utc::now();
+    files
+        .iter()
+        .map(|file| {
+            let state = file.state().ok()?;
+            // a date in the future is recent too
+            let recent = now
+                .signed_duration_since(state.modified)
+                .to_std()
+                .map_or(true, |age| age < settle);
+            Some((state, recent))
+        })
+        .collect()
+}
+
+fn split_changed(
+    files: Vec<FileHelper>,
+    before: Vec<Option<(FileState, bool)>>,
+) -> (Vec<FileHelper>, Vec<FileHelper>) {
+    let mut stable = vec![];
+    let mut unstable = vec![];
+    for (file, before) in files.into_iter().zip(before) {
+        match before {
+            Some((_, false)) => stable.push(file),
+            Some((before, true)) if file.state().is_ok_and(|after| after == before) => {
+                stable.push(file)
+            }
+            _ => unstable.push(file),
+        }
+    }
+    (stable, unstable)
+}
+
 fn read_file_to_combine_config<F>(
     config: &mut Config,
     path: &Path,
@@ -168,6 +378,10 @@ fn read_file_to_combine_config<F>(
 where
     F: FnOnce(&str) -> Result<Config, FOError>,
 {
+    // "./".with_extension("yaml") is still "./"
+    if path.is_dir() {
+        return Ok(());
+    }
     let yaml = fs::read_to_string(path);
     match yaml {
         Ok(yaml) => {
@@ -181,6 +395,64 @@ where
     Ok(())
 }
 
+#[cfg(test)]
+pub mod test_util {
+    use std::{
+        fs,
+        ops::Deref,
+        path::{Path, PathBuf},
+    };
+
+    use crate::db::IndexDB;
+
+    /// A folder of its own for a test, in the temp folder. it's removed when
+    /// dropped, so a test that failed doesn't leave it behind
+    pub struct TestRoot(PathBuf);
+
+    impl TestRoot {
+        pub fn new(name: &str) -> Self {
+            let root = std::env::temp_dir().join(format!("picofo-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&root);
+            fs::create_dir_all(&root).unwrap();
+            Self(root)
+        }
+
+        /// (path, content) of the files, their folders are made too
+        pub fn write<C: AsRef<[u8]>>(&self, files: &[(&str, C)]) {
+            for (name, content) in files {
+                let path = self.0.join(name);
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(path, content).unwrap();
+            }
+        }
+
+        /// fo.db of the folder
+        pub async fn db(&self) -> IndexDB {
+            IndexDB::open(&self.0).await.unwrap()
+        }
+    }
+
+    impl Deref for TestRoot {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl AsRef<Path> for TestRoot {
+        fn as_ref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TestRoot {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+}
+
 #[test]
 fn test_cutter() {
     assert_eq!(
@@ -201,6 +473,15 @@ fn test_cutter() {
     dbg!(PathBuf::from("C:/document/s\\data1").format());
 }
 
+#[test]
+fn test_relative_to() {
+    assert_eq!(
+        relative_to("/a/b/c.txt", "/a/d/e"),
+        PathBuf::from("../../b/c.txt")
+    );
+    assert_eq!(relative_to("/a/b/c.txt", "/a/b"), PathBuf::from("c.txt"));
+}
+
 #[test]
 fn test_read_config() {
     let file_helper = FileHelper::new("./testdir/test2");
@@ -232,6 +513,7 @@ pub struct FieldHashMapBuilder {
     // field, (schema_notation, data)
     list: HashMap<String, (String, String)>,
     schema_name: String,
+    notation: Notation,
 }
 
 impl FieldHashMapBuilder {
@@ -239,8 +521,16 @@ impl FieldHashMapBuilder {
         Self {
             list: HashMap::new(),
             schema_name: String::from(name.as_ref()),
+            notation: Notation::default(),
         }
     }
+
+    /// how the schema name in notation is matched, call before insert
+    pub fn notation(mut self, notation: Notation) -> Self {
+        self.notation = notation;
+        self
+    }
+
     pub fn insert(mut self, data: &Vec<(String, String)>) -> Self {
         // let mut map = HashMap::new();
         for (field, data) in data {
@@ -253,7 +543,7 @@ impl FieldHashMapBuilder {
                 ("".to_owned(), field.to_owned())
             };
             // let schema_notation = field.replace(".", "_");
-            if (match_text(&schema_notation, &self.schema_name)) {
+            if notation_match(&schema_notation, &self.schema_name, self.notation) {
                 self.list
                     .entry(field.to_owned())
                     .and_modify(|(item_schema_notation, item_data)| {
@@ -312,3 +602,59 @@ fn test_field_dot_list() {
     let mut helper = FieldHashMapBuilder::new("abc").insert(&data);
     dbg!(helper.to_map());
 }
+
+#[async_std::test]
+async fn test_split_stable() {
+    let dir = test_util::TestRoot::new("stable");
+    fs::write(dir.join("done.mkv"), "a").unwrap();
+    fs::write(dir.join("growing.mkv"), "a").unwrap();
+    fs::write(dir.join("video.mkv.part"), "a").unwrap();
+    let hour_ago = std::time::SystemTime::now() - Duration::from_secs(3600);
+    let done = fs::File::options()
+        .write(true)
+        .open(dir.join("done.mkv"))
+        .unwrap();
+    done.set_modified(hour_ago).unwrap();
+    let files = || {
+        vec![
+            FileHelper::new(dir.join("done.mkv")),
+            FileHelper::new(dir.join("growing.mkv")),
+        ]
+    };
+
+    // growing.mkv is written again between the two looks
+    let before = snapshot(&files(), Duration::from_secs(60));
+    fs::write(dir.join("growing.mkv"), "ab").unwrap();
+    let (stable, unstable) = split_changed(files(), before);
+    assert_eq!(stable[0].file_name(), "done.mkv");
+    assert_eq!(unstable[0].file_name(), "growing.mkv");
+
+    // a file older than the settle period is not waited for, this would take half an hour
+    let settle = Duration::from_secs(1800);
+    let (stable, unstable) =
+        split_stable(vec![FileHelper::new(dir.join("done.mkv"))], settle).await;
+    assert_eq!((stable.len(), unstable.len()), (1, 0));
+
+    let extensions = vec!["part".to_owned()];
+    assert!(FileHelper::new(dir.join("video.mkv.part")).is_temp(&extensions));
+    assert!(!FileHelper::new(dir.join("done.mkv")).is_temp(&extensions));
+}
+
+#[test]
+fn test_partial_md5() {
+    let root = test_util::TestRoot::new("partial_md5");
+    let small = FileHelper::new(root.join("small"));
+    fs::write(small.get_path(), "small").unwrap();
+    assert_eq!(small.partial_md5().unwrap(), small.md5().unwrap());
+
+    // only the middle is different
+    let mut content = vec![1; 3 * HASH_CHUNK as usize];
+    let a = FileHelper::new(root.join("a"));
+    fs::write(a.get_path(), &content).unwrap();
+    content[HASH_CHUNK as usize + 1] = 2;
+    let b = FileHelper::new(root.join("b"));
+    fs::write(b.get_path(), &content).unwrap();
+    assert_eq!(a.partial_md5().unwrap(), b.partial_md5().unwrap());
+    assert_ne!(a.md5().unwrap(), b.md5().unwrap());
+    assert_ne!(a.partial_md5().unwrap(), a.md5().unwrap());
+}
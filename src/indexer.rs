@@ -1,23 +1,144 @@
 use std::{
     cmp::max,
-    collections::HashMap,
+    collections::{BTreeMap, HashMap, HashSet},
     ffi::OsStr,
+    fmt::{Display, Formatter},
     fs,
     hash::Hash,
     path::{Path, PathBuf},
+    sync::{Condvar, Mutex},
+    thread,
+    time::{Duration, Instant},
 };
 
-use chrono::{DateTime, Utc};
+use chrono::Utc;
 
 use crate::{
-    db::IndexDB,
-    helper::{FileHelper, PathHelper},
-    schema::SchemaList,
+    config_reader::{Config, ConfigDatatype, SchemaConfigItem},
+    db::{schema_columns, schema_table, ChildItem, ChildrenList, FileIdentity, FileRow, IndexDB},
+    error::FOError,
+    helper::{FieldHashMapBuilder, FileHelper, PathHelper, FOLDER_CONFIG, HASH_CHUNK},
+    mover::Mover,
+    schema::{Schema, SchemaList},
+    views::VIEWS_FOLDER,
 };
 
+/// rows written in one transaction
+const BATCH: usize = 1000;
+
+/// The folders are read by `jobs` threads at once, then the new rows are
+/// written in the order the folders were found in, so the ids are the same
+/// whatever the number of threads
 pub struct Indexer<'a> {
     db: &'a mut IndexDB,
     schema: SchemaList,
+    jobs: usize,
+    /// rows not found in their place in this run
+    gone: Vec<i32>,
+    /// rows added in this run
+    added: Vec<i32>,
+    /// files not changed whose fields are extracted again, their config did
+    refresh: Vec<Refresh>,
+    definitions: Definitions,
+    stats: IndexStats,
+}
+
+/// what a run did, and how fast
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct IndexStats {
+    /// files and folders looked at
+    pub entries: usize,
+    pub added: usize,
+    /// files with new fields from a changed config
+    pub refreshed: usize,
+    /// files changed in place, their row is kept
+    pub updated: usize,
+    pub moved: usize,
+    pub deleted: usize,
+    pub elapsed: Duration,
+}
+
+impl Display for IndexStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let seconds = self.elapsed.as_secs_f64();
+        write!(
+            f,
+            "{} entries in {:.2}s ({:.0}/s), {} added, {} updated, {} refreshed, {} moved, {} deleted",
+            self.entries,
+            seconds,
+            self.entries as f64 / seconds.max(0.001),
+            self.added,
+            self.updated,
+            self.refreshed,
+            self.moved,
+            self.deleted
+        )
+    }
+}
+
+/// a folder to read, id is None for a folder without a row yet
+struct Job {
+    path: PathBuf,
+    id: Option<i32>,
+    /// the one of the folder with its parents' under it
+    config: Config,
+    /// the config changed since the rows, in the folder or above it
+    config_changed: bool,
+}
+
+/// a folder on the disk compared with its rows
+#[derive(Default)]
+struct ScannedFolder {
+    entries: Vec<Scanned>,
+    /// rows of the folder not found on the disk
+    gone: Vec<i32>,
+    /// files and folders in it
+    seen: usize,
+}
+
+enum Scanned {
+    /// a folder with a row, something in it changed
+    Folder { id: i32, path: PathBuf },
+    /// a file or folder without a row, or a file changed since its row
+    New(Box<NewRow>),
+    /// a file not changed, with the fields of a config that did
+    Fields(Refresh),
+}
+
+struct Refresh {
+    id: i32,
+    path: PathBuf,
+    fields: Fields,
+    schemas: Vec<Schema>,
+}
+
+struct NewRow {
+    /// the row of a file changed in place, it's updated instead of added
+    id: Option<i32>,
+    path: PathBuf,
+    row: FileRow,
+    fields: Fields,
+    schemas: Vec<Schema>,
+    /// `_schema` of the file's own config
+    schema_items: Vec<(String, SchemaConfigItem)>,
+}
+
+/// columns of the schema tables in a run, with where they come from.
+/// a schema has one table, two folders can't give it other fields
+type Definitions = HashMap<String, (Vec<(String, &'static str)>, PathBuf)>;
+
+/// where the row of the parent is, or will be
+#[derive(Clone, Copy)]
+enum Parent {
+    Row(i32),
+    /// the nth new row
+    Added(usize),
+}
+
+/// folders waiting to be read and the number of them being read
+struct Queue {
+    waiting: Vec<Job>,
+    running: usize,
 }
 
 impl<'a> Indexer<'a> {
@@ -25,89 +146,576 @@ impl<'a> Indexer<'a> {
         Self {
             db,
             schema: SchemaList::new(),
+            jobs: default_jobs(),
+            gone: vec![],
+            added: vec![],
+            refresh: vec![],
+            definitions: HashMap::new(),
+            stats: IndexStats::default(),
         }
     }
 
+    /// number of folders read at once
+    pub fn with_jobs(mut self, jobs: usize) -> Self {
+        self.jobs = jobs.max(1);
+        self
+    }
+
     /// path please start as ./ the working directory is already saved in db
     /// if dir is not exists, dir_index is -1
-    #[async_recursion::async_recursion]
     pub async fn indexing<P: AsRef<Path> + std::marker::Send>(
         &mut self,
         path: P,
         parent_index: i32,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let path = PathBuf::from(path.as_ref());
-        // let path = path.into();
-        dbg!(self.db.get_path_new(), &path);
-        let full_path = self.db.get_path_new().join(path);
-        dbg!(&full_path);
-        // let full_path = self.db.get_path().to_owned() + &path;
-        let dir = fs::read_dir(&full_path)?;
-
-        let db_children = self.db.children(parent_index).await?;
-        let mut db_children_checker: HashMap<i32, bool> =
-            db_children.values().map(|v| (v.id, false)).collect();
-
-        for item in dir {
-            dbg!(&item);
-            let item = item?;
-            let item_full_path = &item.path();
-            let file_helper = FileHelper::new(item_full_path);
-            let item_cut_path = item_full_path.cut(self.db.get_path_new());
-            let file_name = file_helper.file_name();
-
-            // ignore fo.db
-            if file_name.eq("fo.db") {
-                continue;
+    ) -> Result<IndexStats, Box<dyn std::error::Error>> {
+        let start = Instant::now();
+        self.stats = IndexStats::default();
+        // config of the folder, the ones of its parents are not read
+        let root = self.db.get_path_new();
+        let config = FileHelper::new(root.join(path.as_ref())).read_config()?;
+        let schemalist = SchemaList::from(&config.schema);
+        self.definitions.clear();
+        for schema in schemalist.list.values() {
+            define(&mut self.definitions, schema, path.as_ref())?;
+        }
+        self.db.sync_schemas(&schemalist).await?;
+
+        // the disk is read first, nothing is written until it's done
+        let tree = self.db.all_children().await?;
+        let first = Job {
+            path: PathBuf::from(path.as_ref()),
+            id: Some(parent_index),
+            config,
+            config_changed: false,
+        };
+        let jobs = self.jobs;
+        let mut found =
+            async_std::task::spawn_blocking(move || scan(&root, &tree, first, jobs)).await?;
+
+        let mut rows = vec![];
+        self.order(
+            &mut found,
+            path.as_ref(),
+            Parent::Row(parent_index),
+            &mut rows,
+        );
+        // nothing is written with a schema defined twice
+        let defined =
+            rows.iter()
+                .flat_map(|(_, new)| new.schemas.iter().map(|schema| (schema, &new.path)))
+                .chain(self.refresh.iter().flat_map(|refresh| {
+                    refresh.schemas.iter().map(|schema| (schema, &refresh.path))
+                }));
+        // a table for each schema once, the root ones are synced already
+        let mut tables = HashMap::new();
+        for (schema, path) in defined {
+            define(&mut self.definitions, schema, path)?;
+            if !schemalist.list.contains_key(&schema.name) {
+                tables.entry(&schema.name).or_insert(schema);
             }
+        }
+        for schema in tables.into_values() {
+            self.db.sync_schema(schema).await?;
+        }
+        self.write(rows).await?;
+        self.track_moves().await?;
 
-            let last_mod = file_helper.last_mod().unwrap_or(DateTime::from(Utc::now()));
-            match db_children.get(&file_name) {
-                Some(db_child) if last_mod.timestamp().eq(&db_child.last_modified.timestamp()) => {
-                    dbg!("file/folder already exists and not modified");
-                    db_children_checker
-                        .entry(db_child.id)
-                        .and_modify(|d| *d = true);
-                    continue;
-                }
-                Some(db_child) if db_child.is_folder => {
-                    dbg!("folder already exists but modified");
-                    let dir_id = db_child.id;
-                    self.indexing(&item_cut_path, dir_id).await?;
-                    db_children_checker
-                        .entry(db_child.id)
-                        .and_modify(|d| *d = true);
+        self.stats.elapsed = start.elapsed();
+        eprintln!("indexed {}", self.stats);
+        Ok(self.stats.clone())
+    }
+
+    /// the new rows of the folder and the ones in it, in the order the
+    /// folders were read. each one after its parent
+    fn order(
+        &mut self,
+        found: &mut HashMap<PathBuf, ScannedFolder>,
+        path: &Path,
+        parent: Parent,
+        rows: &mut Vec<(Parent, Box<NewRow>)>,
+    ) {
+        let Some(folder) = found.remove(path) else {
+            return;
+        };
+        self.stats.entries += folder.seen;
+        // the unused items are deleted at the end of the run, unless they were moved
+        self.gone.extend(folder.gone);
+        for entry in folder.entries {
+            match entry {
+                Scanned::Folder { id, path } => self.order(found, &path, Parent::Row(id), rows),
+                Scanned::Fields(refresh) => self.refresh.push(refresh),
+                Scanned::New(new) => {
+                    let folder = new.row.is_folder.then(|| new.path.clone());
+                    rows.push((parent, new));
+                    if let Some(folder) = folder {
+                        self.order(found, &folder, Parent::Added(rows.len() - 1), rows);
+                    }
                 }
-                _ => {
-                    dbg!("adding file/folder");
-
-                    if item_full_path.is_dir() {
-                        let dir_id = self.db.add_folder(&item_cut_path, parent_index).await?;
-                        self.indexing(&item_cut_path, dir_id).await?;
-                    } else {
-                        self.db.add_file(&item_cut_path, parent_index).await?;
-                        // config related
-                        let config = file_helper.read_config()?;
-                        for (name, schemaitem) in config.schema.items {
-                            self.schema.parse_config_item(name, &schemaitem);
-                            // self.schema.add(schema);
+            }
+        }
+    }
+
+    async fn write(&mut self, rows: Vec<(Parent, Box<NewRow>)>) -> Result<(), FOError> {
+        let mut ids: Vec<i32> = Vec::with_capacity(rows.len());
+        let mut added = vec![];
+        for chunk in rows.chunks(BATCH) {
+            let mut batch = self.db.batch().await?;
+            for (parent, new) in chunk {
+                let parent = match parent {
+                    Parent::Row(id) => *id,
+                    Parent::Added(i) => ids[*i],
+                };
+                let id = match new.id {
+                    Some(id) => {
+                        batch.update(id, &new.row, &new.fields).await?;
+                        batch.set_schemas(id, &new.schemas).await?;
+                        self.stats.updated += 1;
+                        id
+                    }
+                    None => {
+                        let id = batch.add(&new.row, parent, &new.fields).await?;
+                        // no schema is the any type, which the row has already
+                        if !new.schemas.is_empty() {
+                            batch.set_schemas(id, &new.schemas).await?;
                         }
+                        added.push(id);
+                        id
                     }
+                };
+                for (name, item) in &new.schema_items {
+                    self.schema.parse_config_item(name.to_owned(), item);
                 }
+                ids.push(id);
+            }
+            batch.commit().await?;
+        }
+        self.stats.added += added.len();
+        self.added.extend(added);
+
+        let refresh = std::mem::take(&mut self.refresh);
+        for chunk in refresh.chunks(BATCH) {
+            let mut batch = self.db.batch().await?;
+            for refresh in chunk {
+                batch.set_fields(refresh.id, &refresh.fields).await?;
+                batch.set_schemas(refresh.id, &refresh.schemas).await?;
+            }
+            batch.commit().await?;
+        }
+        self.stats.refreshed += refresh.len();
+        Ok(())
+    }
+
+    /// The rows gone from their place that are found again among the new ones,
+    /// by inode or by content, take the place of the new ones. so a moved or
+    /// renamed file keep its id and fields. the other gone rows are deleted
+    async fn track_moves(&mut self) -> Result<(), FOError> {
+        let gone = std::mem::take(&mut self.gone);
+        let added = std::mem::take(&mut self.added);
+        if gone.is_empty() {
+            return Ok(());
+        }
+        let gone = self.db.identities(&gone).await?;
+        let added = match added.is_empty() {
+            true => vec![],
+            false => self.db.identities(&added).await?,
+        };
+
+        // where to look for a new row among the gone ones, the first one wins
+        let mut by_inode: HashMap<(Option<i64>, i64), usize> = HashMap::new();
+        let mut by_content: HashMap<(i64, String), Vec<usize>> = HashMap::new();
+        for (i, old) in gone.iter().enumerate() {
+            if let Some(inode) = old.inode {
+                by_inode.entry((old.device, inode)).or_insert(i);
+            }
+            if let (false, Some(size), Some(partial)) = (old.is_folder, old.size, &old.partial_hash)
+            {
+                by_content
+                    .entry((size, partial.clone()))
+                    .or_default()
+                    .push(i);
             }
         }
+        let mut gone = gone.into_iter().map(Some).collect::<Vec<_>>();
 
-        // deleted the unused item
-        dbg!("deleting...");
-        for (key, _) in db_children_checker.iter().filter(|(_, d)| !**d) {
-            self.db.delete(*key).await?;
-            dbg!(key);
+        for new in &added {
+            let mut found = new
+                .inode
+                .and_then(|inode| by_inode.get(&(new.device, inode)).copied())
+                .filter(|&i| gone[i].as_ref().is_some_and(|old| same_inode(old, new)));
+            let content = match (new.is_folder, new.size, &new.partial_hash) {
+                (false, Some(size), Some(partial)) => by_content.get(&(size, partial.clone())),
+                _ => None,
+            };
+            if found.is_none() {
+                let mut new_hash = None;
+                for &i in content.into_iter().flatten() {
+                    let Some(old) = &gone[i] else {
+                        continue;
+                    };
+                    // the head and tail are the same, only the full hash tell
+                    // it's the same file. unknown, the file is gone with it
+                    let Some(old_hash) = full_hash(old) else {
+                        continue;
+                    };
+                    if old.path == new.path {
+                        continue;
+                    }
+                    if new_hash.is_none() {
+                        new_hash = self
+                            .db
+                            .content_hash(new.id, true)
+                            .await?
+                            .and_then(|h| h.full);
+                    }
+                    if new_hash.as_ref() == Some(&old_hash) {
+                        found = Some(i);
+                        break;
+                    }
+                }
+            }
+            if let Some(old) = found.and_then(|i| gone[i].take()) {
+                self.db.take_over(old.id, new.id).await?;
+                self.stats.moved += 1;
+            }
         }
 
+        // the files in the gone folders are there too, counted with their folder
+        let mut batch = self.db.batch().await?;
+        for (i, old) in gone.iter().flatten().enumerate() {
+            if i > 0 && i % BATCH == 0 {
+                batch.commit().await?;
+                batch = self.db.batch().await?;
+            }
+            batch.delete(old.id).await?;
+            self.stats.deleted += 1;
+        }
+        batch.commit().await?;
         Ok(())
     }
 }
 
+/// the schema as the table of its name in this run, unless it has other fields there
+fn define(definitions: &mut Definitions, schema: &Schema, path: &Path) -> Result<(), FOError> {
+    let columns = schema_columns(schema);
+    match definitions.get(&schema_table(&schema.name)) {
+        Some((defined, _)) if *defined == columns => Ok(()),
+        Some((_, other)) => Err(FOError::SchemaError(format!(
+            "schema {} has other fields for {:?} than for {:?}, one of them should have another name",
+            schema.name, path, other
+        ))),
+        None => {
+            definitions.insert(schema_table(&schema.name), (columns, path.to_path_buf()));
+            Ok(())
+        }
+    }
+}
+
+/// one thread for each core
+pub fn default_jobs() -> usize {
+    thread::available_parallelism().map_or(1, |jobs| jobs.get())
+}
+
+/// Read the folder and the ones in it with jobs threads. tree is the rows of
+/// every folder, by folder id. the folders read, by path
+fn scan(
+    root: &Path,
+    tree: &HashMap<i32, ChildrenList>,
+    first: Job,
+    jobs: usize,
+) -> Result<HashMap<PathBuf, ScannedFolder>, FOError> {
+    let queue = Mutex::new(Queue {
+        waiting: vec![first],
+        running: 0,
+    });
+    let ready = Condvar::new();
+    let found = Mutex::new(HashMap::new());
+    let error = Mutex::new(None);
+
+    thread::scope(|scope| {
+        for _ in 0..jobs {
+            scope.spawn(|| loop {
+                let job = {
+                    let mut queue = queue.lock().unwrap();
+                    loop {
+                        if let Some(job) = queue.waiting.pop() {
+                            queue.running += 1;
+                            break job;
+                        }
+                        // nothing left, and nothing that could add more
+                        if queue.running == 0 {
+                            return;
+                        }
+                        queue = ready.wait(queue).unwrap();
+                    }
+                };
+                let result = scan_folder(root, tree, &job);
+                let mut queue = queue.lock().unwrap();
+                match result {
+                    Ok((folder, folders)) => {
+                        found.lock().unwrap().insert(job.path, folder);
+                        queue.waiting.extend(folders);
+                    }
+                    // the others stop after the folder they are reading
+                    Err(e) => {
+                        error.lock().unwrap().get_or_insert(e);
+                        queue.waiting.clear();
+                    }
+                }
+                queue.running -= 1;
+                ready.notify_all();
+            });
+        }
+    });
+
+    match error.into_inner().unwrap() {
+        Some(e) => Err(e),
+        None => Ok(found.into_inner().unwrap()),
+    }
+}
+
+/// a folder compared with its rows, with the folders in it to read next
+fn scan_folder(
+    root: &Path,
+    tree: &HashMap<i32, ChildrenList>,
+    job: &Job,
+) -> Result<(ScannedFolder, Vec<Job>), FOError> {
+    let empty = ChildrenList::new();
+    let db_children = job.id.and_then(|id| tree.get(&id)).unwrap_or(&empty);
+    let mut unseen = db_children
+        .values()
+        .map(|child| child.id)
+        .collect::<HashSet<_>>();
+    let mut folder = ScannedFolder::default();
+    let mut folders = vec![];
+
+    // a config file of the folder, or next to one in it, is not the one of its row
+    let dir = root.join(&job.path);
+    let changed = |name: &OsStr| {
+        let on_disk = FileHelper::new(dir.join(name)).last_mod();
+        let row = name
+            .to_str()
+            .and_then(|name| db_children.get(name))
+            .map(|child| child.last_modified.timestamp());
+        on_disk.map(|last_mod| last_mod.timestamp()) != row
+    };
+    let config_changed =
+        job.config_changed || FOLDER_CONFIG.iter().any(|name| changed(OsStr::new(name)));
+
+    for item in fs::read_dir(&dir)? {
+        let item = item?;
+        let item_full_path = &item.path();
+        let file_helper = FileHelper::new(item_full_path);
+        let item_cut_path = item_full_path.cut(root);
+        let file_name = file_helper.file_name();
+
+        // ignore fo.db with its backups, and the symlinks of views/
+        if file_name.starts_with("fo.db") || (job.id == Some(0) && file_name.eq(VIEWS_FOLDER)) {
+            continue;
+        }
+        folder.seen += 1;
+
+        let last_mod = file_helper.last_mod().unwrap_or(Utc::now());
+        let same = |child: &ChildItem| last_mod.timestamp() == child.last_modified.timestamp();
+        match db_children.get(&file_name) {
+            Some(db_child) if db_child.is_folder => {
+                unseen.remove(&db_child.id);
+                // the yaml next to the folder is its config too
+                let config_changed = config_changed
+                    || ["yaml", "schema.yaml"]
+                        .iter()
+                        .filter_map(|ext| {
+                            item_full_path
+                                .with_extension(ext)
+                                .file_name()
+                                .map(|name| name.to_owned())
+                        })
+                        .any(|name| changed(&name));
+                // already exists and not modified
+                if same(db_child) && !config_changed {
+                    continue;
+                }
+                folders.push(Job {
+                    path: item_cut_path.clone(),
+                    id: Some(db_child.id),
+                    config: own_config(&job.config, item_full_path)?,
+                    config_changed,
+                });
+                folder.entries.push(Scanned::Folder {
+                    id: db_child.id,
+                    path: item_cut_path,
+                });
+            }
+            Some(db_child) if same(db_child) => {
+                unseen.remove(&db_child.id);
+                if config_changed && !file_helper.is_config() {
+                    let config = own_config(&job.config, item_full_path)?;
+                    let (fields, schemas) = extract_fields(item_full_path, &config)?;
+                    folder.entries.push(Scanned::Fields(Refresh {
+                        id: db_child.id,
+                        path: item_cut_path,
+                        fields,
+                        schemas,
+                    }));
+                }
+            }
+            db_child => {
+                let is_folder = item_full_path.is_dir();
+                // a file changed in place keep its row, a folder in place of
+                // a file is a new one
+                let id = db_child
+                    .filter(|child| !child.is_folder && !is_folder)
+                    .map(|child| child.id);
+                if let Some(id) = id {
+                    unseen.remove(&id);
+                }
+                let (device, inode) = file_helper.inode().unzip();
+                let mut new = Box::new(NewRow {
+                    id,
+                    row: FileRow {
+                        path: item_cut_path.format(),
+                        name: file_name,
+                        last_mod,
+                        is_folder,
+                        size: None,
+                        device,
+                        inode,
+                        partial_hash: None,
+                    },
+                    path: item_cut_path,
+                    fields: vec![],
+                    schemas: vec![],
+                    schema_items: vec![],
+                });
+                if is_folder {
+                    folders.push(Job {
+                        path: new.path.clone(),
+                        id: None,
+                        config: own_config(&job.config, item_full_path)?,
+                        config_changed: false,
+                    });
+                } else {
+                    // a file that can't be read is hashed when the hash is needed
+                    new.row.size = match file_helper.state() {
+                        Ok(state) => Some(state.size),
+                        Err(FOError::IOError(_)) => None,
+                        Err(e) => return Err(e),
+                    };
+                    new.row.partial_hash = match file_helper.partial_md5() {
+                        Ok(hash) => Some(hash),
+                        Err(FOError::IOError(_)) => None,
+                        Err(e) => return Err(e),
+                    };
+                    // config related
+                    let file_config = file_helper.read_config()?;
+                    new.schema_items = file_config.schema.items.clone().into_iter().collect();
+                    if !file_helper.is_config() {
+                        let mut config = job.config.clone();
+                        config.combine_config(&file_config, true);
+                        (new.fields, new.schemas) = extract_fields(item_full_path, &config)?;
+                    }
+                }
+                folder.entries.push(Scanned::New(new));
+            }
+        }
+    }
+    folder.gone = unseen.into_iter().collect();
+    Ok((folder, folders))
+}
+
+/// the same file or folder in another place, a file should have the same size too
+fn same_inode(old: &FileIdentity, new: &FileIdentity) -> bool {
+    old.is_folder == new.is_folder
+        && old.path != new.path
+        && old.inode.is_some()
+        && (old.device, old.inode) == (new.device, new.inode)
+        && (old.is_folder || old.size == new.size)
+}
+
+/// the hash of the whole file if it's known, the partial one is it for a small file
+fn full_hash(file: &FileIdentity) -> Option<String> {
+    match file.size {
+        Some(size) if size as u64 <= 2 * HASH_CHUNK => file.partial_hash.clone(),
+        _ => file.hash.clone(),
+    }
+}
+
+/// (field, value), a list field is there once for each value
+type Fields = Vec<(String, String)>;
+
+/// config of a file or subfolder, its own on top of the parent's
+fn own_config(parent: &Config, path: &Path) -> Result<Config, FOError> {
+    let mut config = parent.clone();
+    config.combine_config(&FileHelper::new(path).read_config()?, true);
+    Ok(config)
+}
+
+/// (field, value) of a file to keep in the any table: what the `_import`
+/// pattern captured with the schema notation resolved, then `_data`, `_tags`
+/// and the fields of its meta.yaml on top. the schemas are the ones it went through
+pub fn extract_fields(path: &Path, config: &Config) -> Result<(Fields, Vec<Schema>), FOError> {
+    let schemalist = SchemaList::from(&config.schema);
+    let meta = FileHelper::new(path).read_meta()?;
+    let (captured, mut schemas) = match Mover::new(path)
+        .with_meta(meta.clone())
+        .get_route(config, &schemalist)
+    {
+        Ok(route) => (route.fields, route.tree.schemas()),
+        Err(_) => (vec![], vec![]),
+    };
+
+    // `anime.name` is name if the file went through anime
+    let mut fields = BTreeMap::new();
+    schemas.retain(|schema| schemalist.get(schema).is_some());
+    let found = schemas
+        .iter()
+        .filter_map(|schema| schemalist.get(schema).cloned())
+        .collect::<Vec<_>>();
+    let schemas = match schemas.is_empty() {
+        true => vec![String::new()],
+        false => schemas,
+    };
+    for schema in &schemas {
+        let notation = schemalist
+            .get(schema)
+            .and_then(|schema| schema.notation)
+            .unwrap_or_default();
+        let resolved = FieldHashMapBuilder::new(schema)
+            .notation(notation)
+            .insert(&captured)
+            .to_map();
+        for (field, value) in resolved {
+            fields.entry(field).or_insert(vec![value]);
+        }
+    }
+
+    for (field, data) in &config.data {
+        let values = match data {
+            ConfigDatatype::Tags(tags) => tags.clone(),
+            ConfigDatatype::String(value) => vec![value.to_owned()],
+            ConfigDatatype::Integer(value) => vec![value.to_string()],
+            ConfigDatatype::Float(value) => vec![value.to_string()],
+        };
+        fields.insert(field.to_owned(), values);
+    }
+    for (field, tags) in &config.tags {
+        let values = fields.entry(field.to_owned()).or_default();
+        for tag in &tags.0 {
+            if !values.contains(tag) {
+                values.push(tag.to_owned());
+            }
+        }
+    }
+    for (field, value) in meta.fields {
+        fields.insert(field, vec![value]);
+    }
+
+    let fields = fields
+        .into_iter()
+        .flat_map(|(field, values)| values.into_iter().map(move |value| (field.clone(), value)))
+        .collect();
+    Ok((fields, found))
+}
+
 /// Newer indexer. old one still works but I don't like it
 /// old one have no schema support, I try to fix that in the new one
 // pub async fn indexer_new(path: db, SchemaList) {
@@ -121,3 +729,473 @@ async fn test_struct() {
     st.indexing("./", 0).await.unwrap();
     dbg!(st.schema);
 }
+
+#[async_std::test]
+async fn test_fields() {
+    let root = crate::helper::test_util::TestRoot::new("fields");
+    let files = [
+        (
+            "_data.yaml",
+            r#"
+            _meta:
+                children: anime
+            _schema:
+                anime:
+                    fields: name, epinum(num)
+                    filename: '%name%/%epinum%.%ext%'
+            _import:
+                - "{?}-{?}.{?}": anime.name, epinum, ext
+            _data:
+                source: web
+            _tags:
+                genre: action, comedy
+            "#,
+        ),
+        ("naruto-01.mkv", ""),
+        ("naruto-01.yaml", "_data:\n    source: bluray"),
+        ("naruto-01.mkv.meta.yaml", "fields:\n    epinum: '1'"),
+        ("sub/_data.yaml", "_tags:\n    genre: drama"),
+        ("sub/bleach-02.mkv", ""),
+    ];
+    root.write(&files);
+
+    let mut db = root.db().await;
+    Indexer::open(&mut db).indexing("./", 0).await.unwrap();
+    async fn fields(db: &IndexDB, path: &str) -> Vec<(String, String)> {
+        let id = db.find_path(path).await.unwrap().unwrap();
+        let mut fields = db.fields(id).await.unwrap();
+        fields.sort();
+        fields
+    }
+    let pairs = |list: &[(&str, &str)]| {
+        list.iter()
+            .map(|(field, value)| (field.to_string(), value.to_string()))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        fields(&db, "./naruto-01.mkv").await,
+        pairs(&[
+            ("epinum", "1"),
+            ("ext", "mkv"),
+            ("genre", "action"),
+            ("genre", "comedy"),
+            ("name", "naruto"),
+            ("source", "bluray"),
+        ])
+    );
+    assert_eq!(
+        fields(&db, "./sub/bleach-02.mkv").await,
+        pairs(&[
+            ("epinum", "02"),
+            ("ext", "mkv"),
+            ("genre", "drama"),
+            ("name", "bleach"),
+            ("source", "web"),
+        ])
+    );
+
+    // refreshed on the next run when the meta.yaml change
+    let meta = root.join("naruto-01.mkv.meta.yaml");
+    fs::write(&meta, "fields:\n    epinum: '2'").unwrap();
+    let later = std::time::SystemTime::now() + std::time::Duration::from_secs(5);
+    let meta = fs::File::options().write(true).open(meta).unwrap();
+    meta.set_modified(later).unwrap();
+    Indexer::open(&mut db).indexing("./", 0).await.unwrap();
+    let naruto = fields(&db, "./naruto-01.mkv").await;
+    assert!(naruto.contains(&("epinum".to_owned(), "2".to_owned())));
+    assert_eq!(naruto.len(), 6);
+
+    // and when the config of a folder is edited in place, for every file under it
+    let id = db.find_path("./sub/bleach-02.mkv").await.unwrap();
+    let config = root.join("sub/_data.yaml");
+    fs::write(&config, "_tags:\n    genre: horror").unwrap();
+    let config = fs::File::options().write(true).open(config).unwrap();
+    config.set_modified(later).unwrap();
+    let stats = Indexer::open(&mut db).indexing("./", 0).await.unwrap();
+    assert_eq!(stats.refreshed, 1);
+    assert_eq!(db.find_path("./sub/bleach-02.mkv").await.unwrap(), id);
+    let bleach = fields(&db, "./sub/bleach-02.mkv").await;
+    assert!(bleach.contains(&("genre".to_owned(), "horror".to_owned())));
+    assert!(!bleach.contains(&("genre".to_owned(), "drama".to_owned())));
+
+    let config = root.join("_data.yaml");
+    let data = fs::read_to_string(&config).unwrap();
+    fs::write(&config, data.replace("source: web", "source: tv")).unwrap();
+    let config = fs::File::options().write(true).open(config).unwrap();
+    config
+        .set_modified(later + std::time::Duration::from_secs(5))
+        .unwrap();
+    let stats = Indexer::open(&mut db).indexing("./", 0).await.unwrap();
+    assert_eq!(stats.refreshed, 2);
+    let bleach = fields(&db, "./sub/bleach-02.mkv").await;
+    assert!(bleach.contains(&("source".to_owned(), "tv".to_owned())));
+    // nothing changed since
+    let stats = Indexer::open(&mut db).indexing("./", 0).await.unwrap();
+    assert_eq!(stats.refreshed, 0);
+}
+
+#[async_std::test]
+async fn test_schema_conflict() {
+    let root = crate::helper::test_util::TestRoot::new("schema-conflict");
+    let files = [
+        (
+            "_data.yaml",
+            r#"
+            _meta:
+                children: anime
+            _schema:
+                anime:
+                    fields: name
+                    filename: '%name%'
+            _import:
+                - "{?}.{?}": name, ext
+            "#,
+        ),
+        ("a.mkv", ""),
+        ("sub/b.mkv", ""),
+    ];
+    root.write(&files);
+    let mut db = root.db().await;
+    Indexer::open(&mut db).indexing("./", 0).await.unwrap();
+
+    // the same name with other fields in a subfolder
+    let sub = "_schema:\n    anime:\n        fields: name, ext\n        filename: '%name%'";
+    fs::write(root.join("sub/_data.yaml"), sub).unwrap();
+    let later = std::time::SystemTime::now() + std::time::Duration::from_secs(5);
+    let sub = fs::File::options()
+        .write(true)
+        .open(root.join("sub/_data.yaml"))
+        .unwrap();
+    sub.set_modified(later).unwrap();
+    let e = Indexer::open(&mut db).indexing("./", 0).await.unwrap_err();
+    assert!(e.to_string().contains("schema anime has other fields"));
+}
+
+#[async_std::test]
+async fn test_changed_in_place() {
+    let root = crate::helper::test_util::TestRoot::new("in-place");
+    fs::write(
+        root.join("_data.yaml"),
+        "_import:\n    - \"{?}-{?}.{?}\": name, epinum, ext",
+    )
+    .unwrap();
+    fs::write(root.join("naruto-01.mkv"), "naruto").unwrap();
+    let mut db = root.db().await;
+    Indexer::open(&mut db).indexing("./", 0).await.unwrap();
+    let id = db.find_path("./naruto-01.mkv").await.unwrap().unwrap();
+    db.content_hash(id, true).await.unwrap();
+    db.set_verified(id, None).await.unwrap();
+
+    // rewritten, the row is the same one with the hashes of the new content
+    fs::write(root.join("naruto-01.mkv"), "naruto, longer").unwrap();
+    let later = std::time::SystemTime::now() + std::time::Duration::from_secs(5);
+    for path in ["naruto-01.mkv", ""] {
+        fs::File::open(root.join(path))
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+    }
+    let stats = Indexer::open(&mut db).indexing("./", 0).await.unwrap();
+    assert_eq!((stats.added, stats.updated, stats.deleted), (0, 1, 0));
+    assert_eq!(db.find_path("./naruto-01.mkv").await.unwrap(), Some(id));
+    let identity = &db.identities(&[id]).await.unwrap()[0];
+    assert_eq!(identity.size, Some(14));
+    assert_eq!(identity.hash, None);
+    assert!(db
+        .fields(id)
+        .await
+        .unwrap()
+        .contains(&("name".to_owned(), "naruto".to_owned())));
+    // verified before, so it's not first in the queue
+    let queue = db.verify_queue().await.unwrap();
+    assert_eq!(queue.last().unwrap().0.id, id);
+}
+
+#[async_std::test]
+async fn test_moves() {
+    let root = crate::helper::test_util::TestRoot::new("moves");
+    fs::create_dir_all(root.join("a/deep")).unwrap();
+    fs::write(
+        root.join("_data.yaml"),
+        "_import:\n    - \"{?}-{?}.{?}\": name, epinum, ext",
+    )
+    .unwrap();
+    fs::write(root.join("a/naruto-01.mkv"), "naruto").unwrap();
+    fs::write(root.join("a/deep/bleach-01.mkv"), "bleach").unwrap();
+    fs::write(root.join("a/onepiece-01.mkv"), "onepiece").unwrap();
+
+    let mut db = root.db().await;
+    Indexer::open(&mut db).indexing("./", 0).await.unwrap();
+    async fn id(db: &IndexDB, path: &str) -> Option<i32> {
+        db.find_path(path).await.unwrap()
+    }
+    let naruto = id(&db, "./a/naruto-01.mkv").await.unwrap();
+    let bleach = id(&db, "./a/deep/bleach-01.mkv").await.unwrap();
+    let onepiece = id(&db, "./a/onepiece-01.mkv").await.unwrap();
+    let deep = id(&db, "./a/deep").await.unwrap();
+    let fields = db.fields(naruto).await.unwrap();
+
+    // renamed into another folder, a folder renamed, and one copied with the
+    // original deleted, found by its content
+    fs::create_dir(root.join("b")).unwrap();
+    fs::rename(root.join("a/naruto-01.mkv"), root.join("b/naruto.mkv")).unwrap();
+    fs::rename(root.join("a/deep"), root.join("a/deeper")).unwrap();
+    fs::copy(root.join("a/onepiece-01.mkv"), root.join("b/onepiece.mkv")).unwrap();
+    fs::remove_file(root.join("a/onepiece-01.mkv")).unwrap();
+    let later = std::time::SystemTime::now() + std::time::Duration::from_secs(5);
+    for folder in ["", "a", "b"] {
+        let folder = fs::File::open(root.join(folder)).unwrap();
+        folder.set_modified(later).unwrap();
+    }
+    Indexer::open(&mut db).indexing("./", 0).await.unwrap();
+
+    assert_eq!(id(&db, "./b/naruto.mkv").await, Some(naruto));
+    assert_eq!(db.fields(naruto).await.unwrap(), fields);
+    assert_eq!(id(&db, "./a/deeper").await, Some(deep));
+    assert_eq!(id(&db, "./a/deeper/bleach-01.mkv").await, Some(bleach));
+    assert_eq!(id(&db, "./b/onepiece.mkv").await, Some(onepiece));
+    for path in ["./a/naruto-01.mkv", "./a/deep", "./a/onepiece-01.mkv"] {
+        assert_eq!(id(&db, path).await, None);
+    }
+    let children = db.all_children().await.unwrap().remove(&deep).unwrap();
+    assert_eq!(children.len(), 1);
+    assert_eq!(children["bleach-01.mkv"].path, "./a/deeper/bleach-01.mkv");
+
+    // the search finds it by the new name
+    let operation = crate::search::Operation::parse("name: naruto.mkv").unwrap();
+    let hits = db
+        .search(&operation, &SchemaList::new(), true)
+        .await
+        .unwrap();
+    assert_eq!(hits.len(), 1);
+    assert_eq!(hits[0].id, naruto);
+
+    // big files with the same head and tail, only the full hash tell them
+    // apart. it's unknown for the first one, so the other file is a new one
+    let big = |middle: u8| {
+        let chunk = HASH_CHUNK as usize;
+        [vec![0; chunk], vec![middle; chunk], vec![0; chunk]].concat()
+    };
+    fs::write(root.join("a/big.bin"), big(1)).unwrap();
+    fs::write(root.join("a/known.bin"), big(3)).unwrap();
+    let later = std::time::SystemTime::now() + std::time::Duration::from_secs(10);
+    fs::File::open(root.join("a"))
+        .unwrap()
+        .set_modified(later)
+        .unwrap();
+    Indexer::open(&mut db).indexing("./", 0).await.unwrap();
+    let big_id = id(&db, "./a/big.bin").await.unwrap();
+    let known = id(&db, "./a/known.bin").await.unwrap();
+    db.content_hash(known, true).await.unwrap();
+
+    // made before the others are deleted, so they don't get their inodes
+    fs::write(root.join("b/other.bin"), big(2)).unwrap();
+    fs::copy(root.join("a/known.bin"), root.join("b/known.bin")).unwrap();
+    fs::remove_file(root.join("a/big.bin")).unwrap();
+    fs::remove_file(root.join("a/known.bin")).unwrap();
+    let later = later + std::time::Duration::from_secs(5);
+    for folder in ["a", "b"] {
+        let folder = fs::File::open(root.join(folder)).unwrap();
+        folder.set_modified(later).unwrap();
+    }
+    Indexer::open(&mut db).indexing("./", 0).await.unwrap();
+    assert_ne!(id(&db, "./b/other.bin").await, Some(big_id));
+    assert!(id(&db, "./b/other.bin").await.is_some());
+    assert_eq!(id(&db, "./a/big.bin").await, None);
+    assert_eq!(id(&db, "./b/known.bin").await, Some(known));
+}
+
+#[async_std::test]
+async fn test_parallel() {
+    let root = crate::helper::test_util::TestRoot::new("parallel");
+    fs::write(
+        root.join("_data.yaml"),
+        "_import:\n    - \"{?}-{?}.{?}\": name, epinum, ext",
+    )
+    .unwrap();
+    for folder in 0..6 {
+        for sub in 0..3 {
+            let path = root.join(format!("show{}/season{}", folder, sub));
+            fs::create_dir_all(&path).unwrap();
+            for file in 0..4 {
+                let content = format!("{} {} {}", folder, sub, file);
+                fs::write(path.join(format!("show{}-{}.mkv", folder, file)), content).unwrap();
+            }
+        }
+    }
+    // every row with its fields
+    async fn rows(db: &IndexDB) -> Vec<(FileIdentity, Vec<(String, String)>)> {
+        let mut rows = vec![];
+        for identity in db.identities(&[0]).await.unwrap() {
+            let fields = db.fields(identity.id).await.unwrap();
+            rows.push((identity, fields));
+        }
+        rows
+    }
+
+    let mut db = root.db().await;
+    let stats = Indexer::open(&mut db)
+        .with_jobs(1)
+        .indexing("./", 0)
+        .await
+        .unwrap();
+    assert_eq!((stats.entries, stats.added), (97, 97));
+    let one = rows(&db).await;
+    drop(db);
+
+    // the same ids with more threads
+    fs::remove_file(root.join("fo.db")).unwrap();
+    let mut db = root.db().await;
+    Indexer::open(&mut db)
+        .with_jobs(8)
+        .indexing("./", 0)
+        .await
+        .unwrap();
+    assert_eq!(rows(&db).await, one);
+
+    let stats = Indexer::open(&mut db)
+        .with_jobs(8)
+        .indexing("./", 0)
+        .await
+        .unwrap();
+    assert_eq!((stats.added, stats.deleted), (0, 0));
+}
+
+#[async_std::test]
+async fn test_index_tables() {
+    let root = crate::helper::test_util::TestRoot::new("tables");
+    let files = [
+        (
+            "_data.yaml",
+            r#"
+            _meta:
+                children: anime
+            _schema:
+                anime:
+                    fields: name, epinum(num)
+                    filename: '%name%/%epinum%.%ext%'
+            _import:
+                - "{?}-{?}.{?}": anime.name, epinum, ext
+            _tags:
+                genre: action, comedy
+            "#,
+        ),
+        ("shows/naruto/naruto-01.mkv", "naruto 1"),
+        ("shows/naruto/naruto-02.mkv", "naruto 2"),
+        (
+            "shows/naruto/naruto-02.mkv.meta.yaml",
+            "fields:\n    epinum: '3'",
+        ),
+        ("shows/bleach-01.mkv", "bleach"),
+        ("shows/bleach-01.yaml", "_data:\n    source: bluray"),
+        ("shows/notes.txt", "no pattern"),
+        (
+            "movies/_data.yaml",
+            r#"
+            _meta:
+                children: movie
+            _schema:
+                movie:
+                    fields: title, year(num)
+                    filename: '%title%.%ext%'
+            _import:
+                - "{?}.{?}.{?}": movie.title, year, ext
+            "#,
+        ),
+        ("movies/akira.1988.mkv", "akira"),
+        ("movies/paprika.2006.mkv", "paprika"),
+        ("_views/all/ignored.mkv", ""),
+    ];
+    root.write(&files);
+    let mut db = root.db().await;
+    let stats = Indexer::open(&mut db)
+        .with_jobs(4)
+        .indexing("./", 0)
+        .await
+        .unwrap();
+    assert_eq!(stats.added, 13);
+
+    // what the indexer wrote, a row as json
+    let pool = sqlx::SqlitePool::connect(&format!("sqlite://{}", root.join("fo.db").display()))
+        .await
+        .unwrap();
+    let rows = |sql: &'static str| {
+        let pool = &pool;
+        async move {
+            let mut rows = sqlx::query(sql)
+                .fetch_all(pool)
+                .await
+                .unwrap()
+                .iter()
+                .map(|row| sqlx::Row::get::<String, usize>(row, 0))
+                .collect::<Vec<_>>();
+            rows.sort();
+            rows
+        }
+    };
+    assert_eq!(
+        rows(
+            "SELECT json_array(files.path, typeList.name) FROM files \
+            JOIN typeList ON typeList.id = files.type WHERE typeList.name != 'any'"
+        )
+        .await,
+        [
+            r#"["./movies/akira.1988.mkv","movie"]"#,
+            r#"["./movies/paprika.2006.mkv","movie"]"#,
+            r#"["./shows/bleach-01.mkv","anime"]"#,
+            r#"["./shows/naruto/naruto-01.mkv","anime"]"#,
+            r#"["./shows/naruto/naruto-02.mkv","anime"]"#,
+        ]
+    );
+    assert_eq!(db.find_path("./_views").await.unwrap(), None);
+    let naruto = db.find_path("./shows/naruto/naruto-02.mkv").await.unwrap();
+    let mut fields = db.fields(naruto.unwrap()).await.unwrap();
+    fields.sort();
+    let expected = [
+        ("epinum", "3"),
+        ("ext", "mkv"),
+        ("genre", "action"),
+        ("genre", "comedy"),
+        ("name", "naruto"),
+    ];
+    assert_eq!(fields, expected.map(|(f, v)| (f.to_owned(), v.to_owned())));
+    assert_eq!(
+        rows(
+            "SELECT json_array(files.path, files_fts.fields) FROM files_fts \
+            JOIN files ON files.id = files_fts.rowid WHERE files_fts.fields != ''"
+        )
+        .await,
+        [
+            r#"["./movies/akira.1988.mkv","mkv action comedy akira 1988"]"#,
+            r#"["./movies/paprika.2006.mkv","mkv action comedy paprika 2006"]"#,
+            r#"["./shows/bleach-01.mkv","01 mkv action comedy bleach bluray"]"#,
+            r#"["./shows/naruto/naruto-01.mkv","01 mkv action comedy naruto"]"#,
+            r#"["./shows/naruto/naruto-02.mkv","3 mkv action comedy naruto"]"#,
+            r#"["./shows/notes.txt","action comedy"]"#,
+        ]
+    );
+    // with the typed columns
+    assert_eq!(
+        rows(
+            "SELECT json_array(files.path, schema_anime.name, epinum) FROM schema_anime \
+            JOIN files ON files.id = file_id"
+        )
+        .await,
+        [
+            r#"["./shows/bleach-01.mkv","bleach",1]"#,
+            r#"["./shows/naruto/naruto-01.mkv","naruto",1]"#,
+            r#"["./shows/naruto/naruto-02.mkv","naruto",3]"#,
+        ]
+    );
+    assert_eq!(
+        rows(
+            "SELECT json_array(files.path, title, year) FROM schema_movie \
+            JOIN files ON files.id = file_id"
+        )
+        .await,
+        [
+            r#"["./movies/akira.1988.mkv","akira",1988]"#,
+            r#"["./movies/paprika.2006.mkv","paprika",2006]"#,
+        ]
+    );
+}
@@ -0,0 +1,239 @@
+// Journal
+// every move done by the organizer is saved in fo.db, so a bad run can be undone.
+// undo walk the run backward and put the file back where it was,
+// unless the file was changed after it was moved.
+// a file overwritten by the organizer can't be brought back.
+// copies and links are just removed, the original is never touched.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use chrono::Utc;
+
+use crate::{
+    config_reader::Action,
+    db::{IndexDB, JournalEntry},
+    error::FOError,
+    helper::FileHelper,
+    organizer::{MoveOutcome, PlannedMove, Resolution},
+};
+
+pub struct Journal<'a> {
+    db: &'a IndexDB,
+    pub run_id: i64,
+}
+
+impl<'a> Journal<'a> {
+    /// start a new run
+    pub async fn start(db: &'a IndexDB) -> Result<Journal<'a>, FOError> {
+        let run_id = db.next_run_id().await?;
+        Ok(Self { db, run_id })
+    }
+
+    /// call this after the file is moved, the file index is updated too.
+    /// the journal row is written last, there is none unless this return Ok
+    pub async fn record(&self, planned: &PlannedMove) -> Result<(), FOError> {
+        let entry = JournalEntry {
+            id: 0,
+            source: self.db.relative_path(&planned.source),
+            destination: self.db.relative_path(&planned.destination),
+            moved_at: Utc::now(),
+            pattern: planned.pattern.clone(),
+            schema: planned.schemas().join("/"),
+            resolution: planned.resolution.as_str().to_owned(),
+            action: planned.action.as_str().to_owned(),
+            state: FileHelper::new(&planned.destination).state()?,
+            undone: false,
+        };
+        if planned.resolution != Resolution::Dedupe {
+            let id = self.db.index_path(&entry.destination).await?;
+            self.db.set_placement(id, &entry.action).await?;
+        }
+        if planned.action == Action::Move {
+            self.db.delete_path(&entry.source).await?;
+        }
+        self.db.add_journal(self.run_id, &entry).await?;
+        Ok(())
+    }
+
+    /// call this after a copy is removed (dedupe) or made a hard link (link) to
+    /// the one kept
+    pub async fn record_duplicate(
+        &self,
+        copy: &Path,
+        kept: &Path,
+        resolution: Resolution,
+    ) -> Result<(), FOError> {
+        let action = match resolution {
+            Resolution::Link => Action::Hardlink,
+            _ => Action::Move,
+        };
+        let entry = JournalEntry {
+            id: 0,
+            source: self.db.relative_path(copy),
+            destination: self.db.relative_path(kept),
+            moved_at: Utc::now(),
+            pattern: String::new(),
+            schema: String::new(),
+            resolution: resolution.as_str().to_owned(),
+            action: action.as_str().to_owned(),
+            state: FileHelper::new(kept).state()?,
+            undone: false,
+        };
+        self.db.add_journal(self.run_id, &entry).await?;
+        if resolution == Resolution::Dedupe {
+            self.db.delete_path(&entry.source).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Undo a run, newest move first. Moves that was already undone are not returned.
+pub async fn undo(db: &IndexDB, run_id: i64) -> Result<Vec<(JournalEntry, MoveOutcome)>, FOError> {
+    let root = db.get_path_new();
+    let mut result = vec![];
+    for entry in db.journal(run_id).await? {
+        if entry.undone {
+            continue;
+        }
+        let outcome = match undo_entry(&root, &entry) {
+            Ok(_) => {
+                db.set_undone(entry.id).await?;
+                // the destination of those is the copy that was kept
+                if !matches!(entry.resolution.as_str(), "dedupe" | "link") {
+                    db.delete_path(&entry.destination).await?;
+                }
+                if entry.action.eq(Action::Move.as_str()) {
+                    db.index_path(&entry.source).await?;
+                }
+                MoveOutcome::Moved
+            }
+            Err(e) => MoveOutcome::Failed(e),
+        };
+        result.push((entry, outcome));
+    }
+    Ok(result)
+}
+
+fn undo_entry(root: &Path, entry: &JournalEntry) -> Result<(), FOError> {
+    let source = root.join(&entry.source);
+    let destination = root.join(&entry.destination);
+
+    // a copy made a link to the one kept, it's a copy of its own again.
+    // copied next to it first, copying over the link would write in both
+    if entry.resolution.eq("link") {
+        check_unchanged(&destination, entry)?;
+        let name = source.file_name().unwrap_or_default().to_string_lossy();
+        let temp = source.with_file_name(format!(".{}.fo-undo", name));
+        fs::copy(&destination, &temp)?;
+        fs::rename(&temp, &source)?;
+        return Ok(());
+    }
+
+    if entry.action.eq(Action::Move.as_str()) {
+        check_unchanged(&destination, entry)?;
+        if source.exists() {
+            return Err(FOError::MoveError(
+                "original path is already taken".to_owned(),
+            ));
+        }
+        if let Some(parent) = source.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // dedupe removed an identical copy, bring it back from the one that stayed
+        if entry.resolution.eq("dedupe") {
+            fs::copy(&destination, &source)?;
+            return Ok(());
+        }
+        fs::rename(&destination, &source)?;
+        let meta = FileHelper::new(&destination).meta_path();
+        if meta.exists() {
+            fs::rename(meta, FileHelper::new(&source).meta_path())?;
+        }
+    } else {
+        if !source.exists() {
+            return Err(FOError::MoveError(
+                "original is missing, this is the only copy left".to_owned(),
+            ));
+        }
+        if entry.action.ends_with("symlink") {
+            let is_symlink = fs::symlink_metadata(&destination)
+                .map_err(|_| FOError::MoveError("file is missing".to_owned()))?
+                .file_type()
+                .is_symlink();
+            if !is_symlink {
+                return Err(FOError::MoveError("file is not a link anymore".to_owned()));
+            }
+        } else {
+            check_unchanged(&destination, entry)?;
+        }
+        fs::remove_file(&destination)?;
+    }
+    remove_empty_parents(root, &destination);
+    Ok(())
+}
+
+fn check_unchanged(path: &Path, entry: &JournalEntry) -> Result<(), FOError> {
+    let state = FileHelper::new(path)
+        .state()
+        .map_err(|_| FOError::MoveError("file is missing".to_owned()))?;
+    if state != entry.state {
+        return Err(FOError::MoveError(
+            "file was changed after it was moved".to_owned(),
+        ));
+    }
+    Ok(())
+}
+
+/// remove the folders the organizer made, stop at the first one that's not empty
+pub fn remove_empty_parents(root: &Path, path: &Path) {
+    let mut parent = path.parent().map(PathBuf::from);
+    while let Some(dir) = parent {
+        if dir.as_path() == root || !dir.starts_with(root) || fs::remove_dir(&dir).is_err() {
+            break;
+        }
+        parent = dir.parent().map(PathBuf::from);
+    }
+}
+
+#[async_std::test]
+async fn test_undo() {
+    use crate::{config_reader::Config, organizer::Organizer, schema::SchemaList};
+
+    let root = crate::helper::test_util::TestRoot::new("undo");
+    fs::write(root.join("a-1.txt"), "a").unwrap();
+    fs::write(root.join("b-2.txt"), "b").unwrap();
+    let config = serde_yaml::from_str::<Config>(
+        r#"
+        _meta:
+            settle: 0
+            children: file
+        _schema:
+            file:
+                filename: '%group%/%name%.txt'
+                fields: group, name
+        _import:
+            - "{?}-{?}.txt": group, name
+        "#,
+    )
+    .unwrap();
+    let sl = SchemaList::from(&config.schema);
+    let organizer = Organizer::new(&root, &config, &sl);
+    let db = root.db().await;
+    let journal = Journal::start(&db).await.unwrap();
+    organizer
+        .apply(&organizer.plan().await.unwrap(), &journal)
+        .await;
+    assert!(root.join("a/1.txt").exists());
+
+    // changed file should stay where it is
+    fs::write(root.join("b/2.txt"), "changed").unwrap();
+    let result = undo(&db, journal.run_id).await.unwrap();
+    assert_eq!(result.len(), 2);
+    assert!(root.join("a-1.txt").exists());
+    assert!(!root.join("a").exists());
+    assert!(root.join("b/2.txt").exists());
+    assert_eq!(db.last_run_id().await.unwrap(), Some(journal.run_id));
+}
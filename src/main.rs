@@ -1,4 +1,4 @@
-use std::{collections::HashSet, error::Error};
+use std::{collections::HashSet, error::Error, path::PathBuf};
 
 use async_std::fs::read_dir;
 use clap::Parser;
@@ -6,7 +6,9 @@ use db::IndexDB;
 
 mod config_reader;
 mod db;
+mod duplicates;
 mod format;
+mod fuzzy;
 mod helper;
 mod indexer;
 mod parser;
@@ -15,9 +17,27 @@ mod search;
 use error::FOError;
 use indexer::Indexer;
 
-use crate::{helper::FileHelper, mover::Mover, schema::SchemaList};
+use crate::{
+    config_reader::{Action, ConflictPolicy, FileMeta},
+    duplicates::Keep,
+    helper::FileHelper,
+    journal::Journal,
+    mover::Mover,
+    organizer::{Organizer, Summary},
+    output::{write_hits, OutputFormat},
+    schema::SchemaList,
+    search::Operation,
+};
 mod error;
+mod journal;
 mod mover;
+mod organizer;
+mod output;
+mod review;
+mod verify;
+mod views;
+#[cfg(target_os = "linux")]
+mod watch;
 
 #[derive(Parser, Debug)]
 struct CliArgs {
@@ -25,6 +45,12 @@ struct CliArgs {
     command: Subcommand,
     #[arg(short, long, default_value = "./")]
     path: String,
+    /// folders the indexer reads at once
+    #[arg(short, long, default_value_t = indexer::default_jobs())]
+    jobs: usize,
+    /// index the tree again before search, views and duplicates, they use fo.db as it is otherwise
+    #[arg(long)]
+    reindex: bool,
 }
 
 // #[derive(Clone, Parser, clap::ValueEnum)]
@@ -35,8 +61,128 @@ struct CliArgs {
 
 #[derive(clap::Subcommand, Debug, Clone)]
 enum Subcommand {
-    Search { search: Vec<String> },
+    /// Search the index, e.g. `name: naruto epinum: 3 | 4`
+    Search {
+        #[arg(required = true)]
+        search: Vec<String>,
+        /// only the words as typed, no typo tolerance
+        #[arg(long)]
+        exact: bool,
+        /// count the results by these fields, e.g. `tags,ext,schema`
+        #[arg(long, value_delimiter = ',')]
+        facets: Vec<String>,
+        #[arg(long, value_enum, default_value_t)]
+        format: OutputFormat,
+        /// columns of --format csv, path, name, modified or any field
+        #[arg(long, value_delimiter = ',', default_value = "path")]
+        columns: Vec<String>,
+        /// full paths instead of paths from the root
+        #[arg(long)]
+        absolute: bool,
+    },
     DebugMove,
+    /// Move files into the place set in schema. Only show the plan unless --apply
+    Organize {
+        #[arg(long, conflicts_with = "apply")]
+        dry_run: bool,
+        #[arg(long)]
+        apply: bool,
+        /// what to do when the destination is taken, overwrite the one in config
+        #[arg(long, value_enum)]
+        conflict: Option<ConflictPolicy>,
+        /// how the file is placed, overwrite the one in config
+        #[arg(long, value_enum)]
+        action: Option<Action>,
+        /// save the plan as json
+        #[arg(long)]
+        plan_out: Option<PathBuf>,
+        /// use a plan saved with --plan-out instead of making a new one
+        #[arg(long, conflicts_with_all = ["conflict", "action", "recursive"])]
+        plan_in: Option<PathBuf>,
+        /// organize the subfolders too, each with its parent config under its own
+        #[arg(long)]
+        recursive: bool,
+        /// ask about every move, the choices are saved in <file>.meta.yaml
+        #[arg(long, conflicts_with = "recursive")]
+        interactive: bool,
+    },
+    /// Organize new files in the root as they arrive
+    #[cfg(target_os = "linux")]
+    Watch {
+        #[arg(long, value_enum)]
+        conflict: Option<ConflictPolicy>,
+        #[arg(long, value_enum)]
+        action: Option<Action>,
+    },
+    /// Put back the files moved by organize, the latest run by default
+    Undo {
+        #[arg(long)]
+        run: Option<i64>,
+    },
+    /// List organize runs, or the moves of one run
+    History {
+        #[arg(long)]
+        run: Option<i64>,
+    },
+    /// Show how a file is routed: patterns tried, schemas visited and the final path
+    Explain {
+        file: PathBuf,
+    },
+    /// Folders of symlinks for the saved searches in `_views`
+    Views {
+        #[command(subcommand)]
+        command: ViewsCommand,
+    },
+    /// Files with the same content. one copy of each is kept with --keep,
+    /// only planned unless --apply
+    Duplicates {
+        #[arg(long, value_enum)]
+        keep: Option<Keep>,
+        /// make the other copies hard links to the one kept instead of removing them
+        #[arg(long, requires = "keep")]
+        hardlink: bool,
+        #[arg(long, requires = "keep")]
+        apply: bool,
+    },
+    /// Hash the files again to find the ones whose content changed while their
+    /// size and modified time didn't. the ones verified the longest time ago first
+    Verify {
+        /// stop after reading this many gigabytes, the next run goes on from there
+        #[arg(long)]
+        max_gb: Option<f64>,
+    },
+    /// fo.db itself
+    Db {
+        #[command(subcommand)]
+        command: DbCommand,
+    },
+}
+
+#[derive(clap::Subcommand, Debug, Clone)]
+enum ViewsCommand {
+    /// Make or refresh _views/<name> for every view, or only the ones given
+    Sync { names: Vec<String> },
+}
+
+#[derive(clap::Subcommand, Debug, Clone)]
+enum DbCommand {
+    /// Run the pending migrations, they also run when fo.db is opened
+    Migrate {
+        /// only list the pending ones, exit with 1 if there are some
+        #[arg(long)]
+        check: bool,
+    },
+}
+
+/// index the tree again when asked with --reindex
+async fn reindex(db: &mut IndexDB, args: &CliArgs) -> Result<(), Box<dyn Error>> {
+    if args.reindex {
+        Indexer::open(db)
+            .with_jobs(args.jobs)
+            .indexing("./", 0)
+            .await?;
+    }
+    Ok(())
 }
 
 // #[derive(Debug, Clone)]
@@ -50,8 +196,41 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let mut recommendation = HashSet::new();
     // dbg!(args);
     match &args.command {
-        Subcommand::Search { search } => {
-            println!("to be implemented")
+        Subcommand::Search {
+            search,
+            exact,
+            facets,
+            format,
+            columns,
+            absolute,
+        } => {
+            let operation = Operation::parse(&search.join(" "))?;
+            // schema tell which fields are numbers
+            let config = FileHelper::new(&args.path).read_config()?;
+            let sl = SchemaList::from(&config.schema);
+            // the indexer cut the paths by the root folder name, "./" has none
+            let mut db = IndexDB::open(&args.path).await?;
+            reindex(&mut db, &args).await?;
+            let hits = db.search(&operation, &sl, *exact).await?;
+            if hits.is_empty() && !args.reindex {
+                recommendation.insert("use --reindex if files changed since they were indexed");
+            }
+            let mut output = std::io::stdout();
+            write_hits(&db, &hits, *format, columns, *absolute, &mut output).await?;
+            if !facets.is_empty() {
+                // stderr when the output is read by another program
+                let mut output: Box<dyn std::io::Write> = match format {
+                    OutputFormat::Plain => Box::new(output),
+                    _ => Box::new(std::io::stderr()),
+                };
+                let ids = hits.iter().map(|hit| hit.id).collect::<Vec<_>>();
+                for facet in db.facets(&ids, facets).await? {
+                    writeln!(output, "\n{}:", facet.field)?;
+                    for (value, count) in facet.counts {
+                        writeln!(output, "  {} {}", count, value)?;
+                    }
+                }
+            }
         }
         Subcommand::DebugMove => {
             //TODO: to fucking do. support for using field before in tree before it used
@@ -73,10 +252,277 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
             // let config = serde_yaml::from_reader(rdr)
         }
+        Subcommand::Organize {
+            apply,
+            conflict,
+            action,
+            plan_out,
+            plan_in,
+            recursive,
+            interactive,
+            ..
+        } => {
+            let helper = FileHelper::new(&args.path);
+            let config = helper.read_config()?;
+            let sl = SchemaList::from(&config.schema);
+            let organizer = Organizer::new(&args.path, &config, &sl)
+                .with_conflict(*conflict)
+                .with_action(*action);
+            let plan = match plan_in {
+                Some(plan_in) => organizer.load_plan(plan_in)?,
+                None if *recursive => organizer.plan_recursive().await?,
+                None => organizer.plan().await?,
+            };
+            let plan = match interactive {
+                true => review::review(
+                    &organizer,
+                    plan,
+                    &mut std::io::stdin().lock(),
+                    &mut std::io::stdout(),
+                )?,
+                false => plan,
+            };
+            if let Some(plan_out) = plan_out {
+                plan.save(plan_out)?;
+                println!("plan saved to {:?}", plan_out);
+            }
+
+            if plan
+                .errors
+                .iter()
+                .any(|(_, e)| matches!(e, FOError::PatternError(_)))
+            {
+                recommendation.insert("don't forgot to add _import and make sure it's valid");
+            }
+
+            let result = if *apply {
+                let db = IndexDB::open(&args.path).await?;
+                let journal = Journal::start(&db).await?;
+                println!("run {}", journal.run_id);
+                organizer.apply(&plan, &journal).await
+            } else {
+                organizer.dry_run(&plan)
+            };
+
+            organizer::report(&plan, &result);
+            if !apply {
+                recommendation.insert("this is a dry run, use --apply to move the files");
+            }
+        }
+        #[cfg(target_os = "linux")]
+        Subcommand::Watch { conflict, action } => {
+            watch::watch(&args.path, *conflict, *action).await?;
+        }
+        Subcommand::Undo { run } => {
+            let db = IndexDB::open(&args.path).await?;
+            let run_id = match run {
+                Some(run) => Some(*run),
+                None => db.last_run_id().await?,
+            };
+            let Some(run_id) = run_id else {
+                println!("nothing to undo");
+                return Ok(());
+            };
+
+            let mut summary = Summary::default();
+            for (entry, outcome) in journal::undo(&db, run_id).await? {
+                println!("{} <- {}: {}", entry.source, entry.destination, outcome);
+                summary.add(&outcome);
+            }
+            println!("run {}: {}", run_id, summary);
+        }
+        Subcommand::History { run } => {
+            let db = IndexDB::open(&args.path).await?;
+            match run {
+                Some(run) => {
+                    for entry in db.journal(*run).await? {
+                        println!(
+                            "{} {}/{} {} -> {} [{}] {}{}",
+                            entry.moved_at.format("%Y-%m-%d %H:%M:%S"),
+                            entry.action,
+                            entry.resolution,
+                            entry.source,
+                            entry.destination,
+                            entry.schema,
+                            entry.pattern,
+                            if entry.undone { " (undone)" } else { "" }
+                        );
+                    }
+                }
+                None => {
+                    for run in db.journal_runs().await? {
+                        println!(
+                            "run {}: {} {} moves, {} undone",
+                            run.run_id,
+                            run.started_at.format("%Y-%m-%d %H:%M:%S"),
+                            run.moves,
+                            run.undone
+                        );
+                    }
+                }
+            }
+        }
+        Subcommand::Explain { file } => {
+            let helper = FileHelper::new(&args.path);
+            let config = helper.read_config()?;
+            let sl = SchemaList::from(&config.schema);
+            let meta = FileHelper::new(file).read_meta()?;
+            if meta != FileMeta::default() {
+                println!("{:?}:", FileHelper::new(file).meta_path());
+                print!("{}", serde_yaml::to_string(&meta)?);
+            }
+            let (explanation, route) = Mover::new(file).with_meta(meta).explain(&config, &sl);
+
+            println!("patterns:");
+            for attempt in &explanation.patterns {
+                let pattern = &attempt.pattern;
+                match &attempt.fields {
+                    None => println!("  {} - no match", pattern),
+                    Some(fields) => {
+                        println!("  {} - matched", pattern);
+                        for (field, value) in fields {
+                            println!("    {} = {}", field, value);
+                        }
+                    }
+                }
+            }
+
+            if !explanation.visits.is_empty() {
+                println!("schemas:");
+            }
+            for visit in &explanation.visits {
+                let indent = "  ".repeat(visit.depth + 1);
+                match &visit.rejected {
+                    None if visit.pinned => println!("{}{} - pinned", indent, visit.schema),
+                    None => println!("{}{} - fit", indent, visit.schema),
+                    Some(reason) => println!("{}{} - rejected: {}", indent, visit.schema, reason),
+                }
+            }
+
+            match route {
+                Ok(route) => println!("-> {}", route.path),
+                Err(e) => {
+                    if let FOError::PatternError(_) = e {
+                        recommendation
+                            .insert("don't forgot to add _import and make sure it's valid");
+                    }
+                    println!("-> not moved: {}", e)
+                }
+            }
+        }
+        Subcommand::Views {
+            command: ViewsCommand::Sync { names },
+        } => {
+            let config = FileHelper::new(&args.path).read_config()?;
+            let sl = SchemaList::from(&config.schema);
+            let mut db = IndexDB::open(&args.path).await?;
+            reindex(&mut db, &args).await?;
+            for view in views::sync(&db, &config, &sl, names).await? {
+                println!(
+                    "{}: {} linked, {} removed, {} kept",
+                    view.name, view.linked, view.removed, view.kept
+                );
+            }
+            if config.views.is_empty() {
+                recommendation.insert("add searches to _views, like `naruto: \"name: naruto\"`");
+            }
+        }
+        Subcommand::Duplicates {
+            keep,
+            hardlink,
+            apply,
+        } => {
+            let config = FileHelper::new(&args.path).read_config()?;
+            let sl = SchemaList::from(&config.schema);
+            let mut db = IndexDB::open(&args.path).await?;
+            reindex(&mut db, &args).await?;
+            let groups = duplicates::find(&db, &config, &sl).await?;
+            let journal = match keep.is_some() && *apply {
+                true => Some(Journal::start(&db).await?),
+                false => None,
+            };
+            if let Some(journal) = &journal {
+                println!("run {}", journal.run_id);
+            }
+
+            let mut summary = Summary::default();
+            for group in &groups {
+                println!("{} bytes, {} copies:", group.size, group.copies.len());
+                for copy in &group.copies {
+                    let fields = copy
+                        .fields
+                        .iter()
+                        .map(|(field, value)| format!("{}={}", field, value))
+                        .collect::<Vec<_>>();
+                    println!(
+                        "  {} [{}{}] {}",
+                        copy.identity.path,
+                        copy.schema,
+                        if copy.organized { ", organized" } else { "" },
+                        fields.join(", ")
+                    );
+                }
+                let Some(keep) = keep else {
+                    continue;
+                };
+                for (path, outcome) in
+                    duplicates::resolve(group, *keep, *hardlink, journal.as_ref()).await
+                {
+                    println!("  -> {}: {}", db.relative_path(path), outcome);
+                    summary.add(&outcome);
+                }
+            }
+
+            match keep {
+                None if !groups.is_empty() => {
+                    recommendation.insert("use --keep newest or --keep organized to keep one copy");
+                }
+                None => println!("no duplicates"),
+                Some(_) => println!("{}", summary),
+            }
+            if keep.is_some() && !apply {
+                recommendation.insert("this is a dry run, use --apply to remove the copies");
+            }
+        }
+        Subcommand::Verify { max_gb } => {
+            let result = verify::verify_root(&args.path, *max_gb).await?;
+            for (path, verdict) in &result {
+                if !matches!(verdict, verify::Verdict::Ok) {
+                    println!("{}: {}", path, verdict);
+                }
+            }
+            println!("{}", verify::summary(&result));
+            if result.iter().any(|(_, verdict)| verdict.is_bad()) {
+                std::process::exit(1);
+            }
+        }
+        Subcommand::Db {
+            command: DbCommand::Migrate { check },
+        } => {
+            // a new fo.db is made up to date, a check doesn't make it
+            let pending = match check {
+                true => IndexDB::pending_in(&args.path).await?,
+                false => IndexDB::connect(&args.path).await?.migrate().await?,
+            };
+            for (version, description) in &pending {
+                println!("{}: {}", version, description);
+            }
+            let version = match check {
+                true => db::MIGRATIONS.len() - pending.len(),
+                false => db::MIGRATIONS.len(),
+            };
+            println!("version {} of {}", version, db::MIGRATIONS.len());
+            if *check && !pending.is_empty() {
+                std::process::exit(1);
+            }
+        }
+    }
+    // stderr, the output of search can be read by another program
+    if !recommendation.is_empty() {
+        eprintln!("tips:");
     }
-    println!("tips:");
     for tip in recommendation {
-        println!(" - {}", tip);
+        eprintln!(" - {}", tip);
     }
     Ok(())
 }
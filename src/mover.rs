@@ -21,31 +21,142 @@ use std::{
 
 // use async_std::path::PathBuf;
 
+use serde::{Deserialize, Serialize};
+
 use crate::{
-    config_reader::Config,
+    config_reader::{Config, FileMeta},
     error::FOError,
     format::PatternString,
+    fuzzy::notation_match,
     helper::match_text,
     schema::{self, Schema, SchemaList},
 };
 
+/// Used when a file match a pattern but no schema fit it
+pub const UNCATEGORIZED: &str = "_Uncategorized";
+
 pub struct Mover {
     path: PathBuf,
+    /// choices from the file's meta.yaml
+    meta: FileMeta,
+}
+
+/// (field, value) pairs
+type Fields = Vec<(String, String)>;
+
+/// pattern name in Route when the file is only routed by its meta.yaml
+pub const META_PATTERN: &str = "meta.yaml";
+
+/// Everything Mover found out about a file
+#[derive(Debug)]
+pub struct Route {
+    /// the `_import` pattern that matched
+    pub pattern: String,
+    /// (field, value) captured by the pattern
+    pub fields: Vec<(String, String)>,
+    pub tree: MoveTree,
+    pub path: String,
 }
 
 impl Mover {
     pub fn new<P: AsRef<Path>>(path: P) -> Self {
         Self {
             path: path.as_ref().to_owned(),
+            meta: FileMeta::default(),
         }
     }
 
+    pub fn with_meta(mut self, meta: FileMeta) -> Self {
+        self.meta = meta;
+        self
+    }
+
     pub fn get_path(
         &self,
         config: &Config,
         schemalist: &SchemaList,
         // schemanames: &Vec<&str>,
     ) -> Result<String, FOError> {
+        Ok(self.get_route(config, schemalist)?.path)
+    }
+
+    pub fn get_route(&self, config: &Config, schemalist: &SchemaList) -> Result<Route, FOError> {
+        self.trace_route(config, schemalist, &mut Explanation::default())
+    }
+
+    /// Same as get_route, but also tell every step that was taken
+    pub fn explain(
+        &self,
+        config: &Config,
+        schemalist: &SchemaList,
+    ) -> (Explanation, Result<Route, FOError>) {
+        let mut explanation = Explanation::default();
+        let route = self.trace_route(config, schemalist, &mut explanation);
+        (explanation, route)
+    }
+
+    fn trace_route(
+        &self,
+        config: &Config,
+        schemalist: &SchemaList,
+        explanation: &mut Explanation,
+    ) -> Result<Route, FOError> {
+        let (pattern, mut fields) = match self.match_pattern(config, explanation)? {
+            Some(found) => found,
+            // the meta.yaml is enough to route the file
+            None if self.meta != FileMeta::default() => (META_PATTERN.to_owned(), vec![]),
+            None => return Err(FOError::PatternError("No pattern match".to_owned())),
+        };
+
+        // edited fields replace the captured one
+        for (field, value) in &self.meta.fields {
+            match fields.iter_mut().find(|(f, _)| f == field) {
+                Some(captured) => captured.1 = value.to_owned(),
+                None => fields.push((field.to_owned(), value.to_owned())),
+            }
+        }
+
+        let meta = config.get_meta(schemalist);
+        let schema_names = meta
+            .other
+            .children
+            .0
+            .iter()
+            .map(|f| f.as_str())
+            .collect::<Vec<&str>>();
+        let pinned = match &self.meta.schema {
+            Some(schema) => schemalist.chain_to(&schema_names, schema).ok_or_else(|| {
+                FOError::SchemaError(format!("pinned schema {} can't be reached", schema))
+            })?,
+            None => vec![],
+        };
+
+        let tree = find_schema(
+            schemalist,
+            &schema_names,
+            &fields,
+            &pinned,
+            0,
+            &mut explanation.visits,
+        );
+        let mut path = tree.to_path(schemalist, &mut vec![]);
+        if let Some(destination) = &self.meta.destination {
+            path = destination.to_owned();
+        }
+        Ok(Route {
+            pattern,
+            fields,
+            tree,
+            path,
+        })
+    }
+
+    /// first `_import` pattern that match, with the (field, value) it captured
+    fn match_pattern(
+        &self,
+        config: &Config,
+        explanation: &mut Explanation,
+    ) -> Result<Option<(String, Fields)>, FOError> {
         // read import config
         for (pattern, var) in &config.import.list {
             // deal with commaseperated
@@ -67,32 +178,45 @@ impl Mover {
                     .to_str()
                     .unwrap(),
             );
-            match matches {
-                Some(matches) => {
-                    let meta = config.get_meta(schemalist);
-                    let schema_names = meta
-                        .other
-                        .children
-                        .0
-                        .iter()
-                        .map(|f| f.as_str())
-                        .collect::<Vec<&str>>();
-
-                    let movetree = schema_finder(&schemalist, &schema_names, &matches);
-
-                    dbg!(&movetree);
-                    return Ok(movetree.unwrap().to_path(&schemalist, &mut vec![]));
-                    // println!("move to {}", movetree.unwrap().to_path(&schemalist));
-                }
-                None => {
-                    continue;
-                }
+            explanation.patterns.push(PatternAttempt {
+                pattern: pattern.to_owned(),
+                fields: matches.clone(),
+            });
+            if let Some(matches) = matches {
+                return Ok(Some((pattern.to_owned(), matches)));
             }
         }
-        Err(FOError::PatternError("No pattern match".to_owned()))
+        Ok(None)
     }
 }
 
+/// A schema that was tried while looking for where a file belong
+#[derive(Debug)]
+pub struct SchemaVisit {
+    /// how deep in the schema tree, 0 is the root children
+    pub depth: usize,
+    pub schema: String,
+    /// why the schema was not used, None if it was
+    pub rejected: Option<String>,
+    /// used because of the file's meta.yaml, fit or not
+    pub pinned: bool,
+}
+
+/// An `_import` pattern that was tried
+#[derive(Debug)]
+pub struct PatternAttempt {
+    pub pattern: String,
+    /// (field, value) captured, None if it didn't match
+    pub fields: Option<Vec<(String, String)>>,
+}
+
+#[derive(Debug, Default)]
+pub struct Explanation {
+    /// every `_import` pattern tried, in order
+    pub patterns: Vec<PatternAttempt>,
+    pub visits: Vec<SchemaVisit>,
+}
+
 #[test]
 fn test_mover() {
     let config = r#"
@@ -123,12 +247,36 @@ fn test_mover() {
     moves.get_path(&parsed_config, &schemalist).unwrap();
 }
 
+#[cfg(test)]
 fn schema_finder(
     schemalist: &SchemaList,
     // schemaname: &str,
     schemalist_name: &Vec<&str>,
     data: &Vec<(String, String)>,
 ) -> Option<MoveTree> {
+    Some(find_schema(
+        schemalist,
+        schemalist_name,
+        data,
+        &[],
+        0,
+        &mut vec![],
+    ))
+}
+
+fn find_schema(
+    schemalist: &SchemaList,
+    schemalist_name: &[&str],
+    data: &Vec<(String, String)>,
+    pinned: &[String],
+    depth: usize,
+    visits: &mut Vec<SchemaVisit>,
+) -> MoveTree {
+    // a pinned chain leave no choice until its end
+    let schemalist_name = match pinned.first() {
+        Some(name) => vec![name.as_str()],
+        None => schemalist_name.to_vec(),
+    };
     let schemas_from_names = schemalist_name
         .iter()
         .filter_map(|name| schemalist.get(name))
@@ -137,7 +285,17 @@ fn schema_finder(
     for schema in schemas_from_names {
         let related_data = prune_unrelated_data(data, &schema.name);
         // dbg!(&related_data, &schema);
-        if schema.is_fit(&related_data) {
+        let rejected = match pinned.is_empty() {
+            true => schema.fit_error(&related_data),
+            false => None,
+        };
+        visits.push(SchemaVisit {
+            depth,
+            schema: schema.name.clone(),
+            rejected,
+            pinned: !pinned.is_empty(),
+        });
+        if visits.last().unwrap().rejected.is_none() {
             let (data_pruned, data_rest) = prune_data(&data, &schema);
 
             let schema_names = schemalist
@@ -150,24 +308,26 @@ fn schema_finder(
                 })
                 .collect::<Vec<&str>>();
 
-            let a = schema_finder(schemalist, &schema_names, &data_rest);
-            match a {
-                None => continue,
-                Some(a) => {
-                    return Some(MoveTree {
-                        name: schema.name.clone(),
-                        fields: remove_dot(&data_pruned),
-                        children: Some(Box::new(a)),
-                    });
-                }
-            }
+            let a = find_schema(
+                schemalist,
+                &schema_names,
+                &data_rest,
+                pinned.get(1..).unwrap_or_default(),
+                depth + 1,
+                visits,
+            );
+            return MoveTree {
+                name: schema.name.clone(),
+                fields: remove_dot(&data_pruned),
+                children: Some(Box::new(a)),
+            };
         }
     }
-    Some(MoveTree {
-        name: "_Uncategorized".to_owned(),
+    MoveTree {
+        name: UNCATEGORIZED.to_owned(),
         fields: remove_dot(&data),
         children: None,
-    })
+    }
 }
 
 fn remove_dot(data: &Vec<(String, String)>) -> Vec<(String, String)> {
@@ -218,7 +378,11 @@ fn prune_data(
             let schema_name = key.split(".").nth(0).unwrap();
             let data_field = key.split(".").nth(1).unwrap();
 
-            if match_text(schema_name, &schema.name) {
+            if notation_match(
+                schema_name,
+                &schema.name,
+                schema.notation.unwrap_or_default(),
+            ) {
                 return schema_fields.contains(&data_field);
             } else {
                 return false;
@@ -269,10 +433,11 @@ macro_rules! string {
 // }
 
 // #[derive(Debug)]
-struct MoveTree {
-    name: String,
-    fields: Vec<(String, String)>,
-    children: Option<Box<MoveTree>>,
+#[derive(Clone, Serialize, Deserialize)]
+pub struct MoveTree {
+    pub name: String,
+    pub fields: Vec<(String, String)>,
+    pub children: Option<Box<MoveTree>>,
 }
 
 impl Debug for MoveTree {
@@ -300,6 +465,16 @@ impl Debug for MoveTree {
 }
 
 impl MoveTree {
+    /// schema names from the top of the tree to the bottom
+    pub fn schemas(&self) -> Vec<String> {
+        let mut names = vec![self.name.clone()];
+        match &self.children {
+            Some(children) if children.name != UNCATEGORIZED => names.extend(children.schemas()),
+            _ => (),
+        }
+        names
+    }
+
     fn to_path(&self, sl: &SchemaList, field: &mut Vec<(String, String)>) -> String {
         // TODO: support lower char
 
@@ -317,7 +492,9 @@ impl MoveTree {
         let str = schema.generate_string(&field);
         if self.children.is_some() {
             let child_path = self.children.as_ref().unwrap().to_path(sl, field);
-            if child_path.is_empty() {
+            if str.is_empty() {
+                child_path
+            } else if child_path.is_empty() {
                 str
             } else {
                 format!("{}/{}", str, child_path)
@@ -394,3 +571,59 @@ fn finder_test() {
     //     "Root".to_owned()
     // ));
 }
+
+#[test]
+fn test_explain() {
+    let config = r#"
+        _meta:
+            children: show
+        _schema:
+            show:
+                filename: '%name%'
+                children: episode
+                fields: name!, season(num)
+            episode:
+                filename: '%ep%.%ext%'
+                fields: ep(num)!, ext
+        _import:
+            - "{?} S{?} - {?}.{mp4}": name, season, ep, ext
+            - "{?}.{mkv}": name, ext
+    "#;
+    let config = serde_yaml::from_str::<Config>(config).unwrap();
+    let sl = SchemaList::from(&config.schema);
+
+    let (explanation, route) = Mover::new("./Show SX - 01.mp4").explain(&config, &sl);
+    assert_eq!(route.unwrap().pattern, "{?} S{?} - {?}.{mp4}");
+    assert_eq!(explanation.visits.len(), 1);
+    assert_eq!(
+        explanation.visits[0].rejected.as_deref(),
+        Some("season is \"X\", expected num")
+    );
+
+    let (explanation, route) = Mover::new("./Show S1 - xx.mp4").explain(&config, &sl);
+    // explain only tells, the path is the one organize use
+    let route = route.unwrap();
+    assert_eq!(
+        route.path,
+        Mover::new("./Show S1 - xx.mp4")
+            .get_route(&config, &sl)
+            .unwrap()
+            .path
+    );
+    assert_eq!(route.path, "Show");
+    assert!(explanation.visits[0].rejected.is_none());
+    assert_eq!(explanation.visits[1].depth, 1);
+    assert_eq!(
+        explanation.visits[1].rejected.as_deref(),
+        Some("ep is \"xx\", expected num")
+    );
+
+    let (explanation, route) = Mover::new("./Show.mkv").explain(&config, &sl);
+    assert!(explanation.patterns[0].fields.is_none());
+    assert!(explanation.patterns[1].fields.is_some());
+    assert_eq!(
+        explanation.visits[1].rejected.as_deref(),
+        Some("missing forced field ep")
+    );
+    assert!(route.is_ok());
+}
@@ -0,0 +1,1033 @@
+// How organizing work
+// 1. list the files in the root (config files and fo.db are left alone)
+//    files still being downloaded or written are left for later
+// 2. ask Mover where each file should go, this is the "plan"
+//    choices saved in the file's meta.yaml (organize --interactive) come first
+// 3. look for conflict in the whole plan (two files going to the same place,
+//    or the place is already taken) and solve them with the conflict policy
+// 4. if applying, create the folders and rename the files one by one
+//    a failed file is reported and the rest keep going
+// 5. every move is written to the journal so it can be undone
+// the plan can also be saved as json, reviewed, and applied later.
+// a file that changed since the plan was made is skipped.
+// with --recursive every folder is organized too, each folder use its parent
+// config with its own _data.yaml, _schema.yaml and _meta.yaml on top. destinations
+// start from the closest folder that has its own config.
+
+use std::{
+    collections::HashMap,
+    fmt::{Display, Formatter},
+    fs,
+    path::{Component, Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config_reader::{Action, Config, ConflictPolicy},
+    error::FOError,
+    helper::{relative_to, split_stable, FileHelper, FileState},
+    journal::{remove_empty_parents, Journal},
+    mover::{MoveTree, Mover, UNCATEGORIZED},
+    schema::{Schema, SchemaList},
+    views::VIEWS_FOLDER,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlannedMove {
+    pub source: PathBuf,
+    pub destination: PathBuf,
+    /// the `_import` pattern that matched
+    pub pattern: String,
+    /// (field, value) captured by the pattern
+    pub fields: Vec<(String, String)>,
+    pub tree: MoveTree,
+    pub resolution: Resolution,
+    pub action: Action,
+    /// the source when it was planned, it's not moved if it changed since then
+    pub state: FileState,
+}
+
+impl PlannedMove {
+    /// schema chain from MoveTree
+    pub fn schemas(&self) -> Vec<String> {
+        self.tree.schemas()
+    }
+}
+
+/// How the move deal with the destination
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Resolution {
+    Move,
+    /// moved to a new name because the destination is taken
+    Rename,
+    /// replace the file at the destination
+    Overwrite,
+    /// identical to the file at the destination, the source is removed
+    Dedupe,
+    /// identical to the file at the destination, the source is made a hard link to it
+    Link,
+    Skip(String),
+}
+
+impl Resolution {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Resolution::Move => "move",
+            Resolution::Rename => "rename",
+            Resolution::Overwrite => "overwrite",
+            Resolution::Dedupe => "dedupe",
+            Resolution::Link => "link",
+            Resolution::Skip(_) => "skip",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum MoveOutcome {
+    Moved,
+    /// identical copy removed
+    Removed,
+    /// identical copy replaced by a hard link
+    Linked,
+    /// dry run, nothing touched
+    Planned,
+    Skipped(String),
+    Failed(FOError),
+}
+
+impl Display for MoveOutcome {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MoveOutcome::Moved => write!(f, "moved"),
+            MoveOutcome::Removed => write!(f, "removed (identical copy)"),
+            MoveOutcome::Linked => write!(f, "linked (identical copy)"),
+            MoveOutcome::Planned => write!(f, "planned"),
+            MoveOutcome::Skipped(reason) => write!(f, "skipped ({})", reason),
+            MoveOutcome::Failed(e) => write!(f, "failed ({})", e),
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct MovePlan {
+    pub moves: Vec<PlannedMove>,
+    /// files that Mover can't find a place for
+    #[serde(skip)]
+    pub errors: Vec<(PathBuf, FOError)>,
+}
+
+impl MovePlan {
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), FOError> {
+        let file = fs::File::create(path)?;
+        serde_json::to_writer_pretty(file, self)?;
+        Ok(())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<MovePlan, FOError> {
+        let file = fs::File::open(path)?;
+        Ok(serde_json::from_reader(file)?)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub moved: usize,
+    pub removed: usize,
+    pub linked: usize,
+    pub planned: usize,
+    pub skipped: usize,
+    pub failed: usize,
+}
+
+impl Summary {
+    pub fn add(&mut self, outcome: &MoveOutcome) {
+        match outcome {
+            MoveOutcome::Moved => self.moved += 1,
+            MoveOutcome::Removed => self.removed += 1,
+            MoveOutcome::Linked => self.linked += 1,
+            MoveOutcome::Planned => self.planned += 1,
+            MoveOutcome::Skipped(_) => self.skipped += 1,
+            MoveOutcome::Failed(_) => self.failed += 1,
+        }
+    }
+}
+
+impl Display for Summary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} moved, {} removed, {} planned, {} skipped, {} failed",
+            self.moved, self.removed, self.planned, self.skipped, self.failed
+        )?;
+        // only duplicates make links
+        if self.linked > 0 {
+            write!(f, ", {} linked", self.linked)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct Organizer<'a> {
+    root: PathBuf,
+    config: &'a Config,
+    schemalist: &'a SchemaList,
+    /// overwrite the policy set in config
+    conflict: Option<ConflictPolicy>,
+    /// overwrite the action set in config
+    action: Option<Action>,
+}
+
+impl<'a> Organizer<'a> {
+    pub fn new<P: AsRef<Path>>(root: P, config: &'a Config, schemalist: &'a SchemaList) -> Self {
+        Self {
+            root: root.as_ref().to_owned(),
+            config,
+            schemalist,
+            conflict: None,
+            action: None,
+        }
+    }
+
+    pub fn with_conflict(mut self, conflict: Option<ConflictPolicy>) -> Self {
+        self.conflict = conflict;
+        self
+    }
+
+    pub fn with_action(mut self, action: Option<Action>) -> Self {
+        self.action = action;
+        self
+    }
+
+    /// Compute where every file in the root should go. Nothing is moved.
+    pub async fn plan(&self) -> Result<MovePlan, FOError> {
+        Ok(self
+            .plan_files(FileHelper::new(&self.root).read_dir()?)
+            .await)
+    }
+
+    /// Same as plan, for some files only
+    pub async fn plan_files(&self, files: Vec<FileHelper>) -> MovePlan {
+        let mut plan = MovePlan::default();
+        let files = self.without_temp(files, &mut plan);
+
+        let (files, unstable) = split_stable(files, self.config.meta.settle()).await;
+        for file in unstable {
+            plan.errors.push((file.get_path().to_owned(), unfinished()));
+        }
+
+        self.plan_stable(files, &mut plan);
+        plan.moves.sort_by(|a, b| a.source.cmp(&b.source));
+        self.resolve_conflicts(&mut plan.moves, &mut HashMap::new());
+        plan
+    }
+
+    /// Same as plan, but every folder under the root too.
+    /// Each folder use the config of its parents with its own on top
+    pub async fn plan_recursive(&self) -> Result<MovePlan, FOError> {
+        let folders = walk(&self.root, self.config)?;
+        let schemalists = folders
+            .iter()
+            .map(|folder| SchemaList::from(&folder.config.schema))
+            .collect::<Vec<_>>();
+        let organizers = folders
+            .iter()
+            .zip(&schemalists)
+            .map(|(folder, schemalist)| Organizer {
+                root: folder.library.clone(),
+                config: &folder.config,
+                schemalist,
+                conflict: self.conflict,
+                action: self.action,
+            })
+            .collect::<Vec<_>>();
+
+        let mut plan = MovePlan::default();
+        let mut files = vec![];
+        for (folder, organizer) in folders.iter().zip(&organizers) {
+            let in_folder = FileHelper::new(&folder.path).read_dir()?;
+            files.extend(organizer.without_temp(in_folder, &mut plan));
+        }
+
+        // wait for every file at once, not folder by folder
+        let (files, unstable) = split_stable(files, self.config.meta.settle()).await;
+        for file in unstable {
+            plan.errors.push((file.get_path().to_owned(), unfinished()));
+        }
+
+        // destination can be taken by a file from another folder
+        let mut taken = HashMap::new();
+        for (folder, organizer) in folders.iter().zip(&organizers) {
+            let mut folder_plan = MovePlan::default();
+            let in_folder = files
+                .iter()
+                .filter(|file| file.get_path().parent() == Some(folder.path.as_path()))
+                .map(|file| FileHelper::new(file.get_path()))
+                .collect();
+            organizer.plan_stable(in_folder, &mut folder_plan);
+            folder_plan.moves.sort_by(|a, b| a.source.cmp(&b.source));
+            organizer.resolve_conflicts(&mut folder_plan.moves, &mut taken);
+            plan.moves.extend(folder_plan.moves);
+            plan.errors.extend(folder_plan.errors);
+        }
+        Ok(plan)
+    }
+
+    /// only the files that can be organized, unfinished downloads go to the plan errors
+    fn without_temp(&self, files: Vec<FileHelper>, plan: &mut MovePlan) -> Vec<FileHelper> {
+        let temp_extensions = self.config.meta.temp_extensions();
+        let mut files = files
+            .into_iter()
+            .filter(|file| file.get_path().is_file() && !file.is_config())
+            .collect::<Vec<_>>();
+        files.retain(|file| {
+            let is_temp = file.is_temp(&temp_extensions);
+            if is_temp {
+                plan.errors.push((file.get_path().to_owned(), unfinished()));
+            }
+            !is_temp
+        });
+        files
+    }
+
+    fn plan_stable(&self, files: Vec<FileHelper>, plan: &mut MovePlan) {
+        for file in files {
+            match self.plan_file(&file) {
+                Ok(planned) => plan.moves.push(planned),
+                Err(e) => plan.errors.push((file.get_path().to_owned(), e)),
+            }
+        }
+    }
+
+    /// setting of the deepest schema that set it
+    fn schema_setting<T, F>(&self, schemas: &[String], setting: F) -> Option<T>
+    where
+        F: Fn(&Schema) -> Option<T>,
+    {
+        schemas
+            .iter()
+            .rev()
+            .find_map(|name| setting(self.schemalist.get(name)?))
+    }
+
+    /// policy from cli, then the schema, then _meta
+    fn policy(&self, planned: &PlannedMove) -> ConflictPolicy {
+        self.conflict
+            .or_else(|| self.schema_setting(&planned.schemas(), |s| s.conflict))
+            .or(self.config.meta.other.conflict)
+            .unwrap_or_default()
+    }
+
+    /// Every conflict is solved here, before anything is moved.
+    /// `taken` is destination -> the file that will end up there
+    fn resolve_conflicts(&self, moves: &mut [PlannedMove], taken: &mut HashMap<PathBuf, PathBuf>) {
+        for planned in moves.iter_mut() {
+            if planned.source == planned.destination {
+                taken.insert(planned.destination.clone(), planned.source.clone());
+                continue;
+            }
+
+            let in_plan = taken.get(&planned.destination).cloned();
+            let occupant = match &in_plan {
+                Some(source) => Some(source.to_owned()),
+                None if planned.destination.exists() => Some(planned.destination.clone()),
+                None => None,
+            };
+
+            if let Some(occupant) = occupant {
+                planned.resolution = match self.policy(planned) {
+                    ConflictPolicy::Skip => {
+                        Resolution::Skip(format!("destination is taken by {:?}", occupant))
+                    }
+                    ConflictPolicy::Rename => {
+                        planned.destination = free_name(&planned.destination, taken);
+                        Resolution::Rename
+                    }
+                    ConflictPolicy::Overwrite if in_plan.is_none() => Resolution::Overwrite,
+                    // never overwrite a file that is moved in the same run
+                    ConflictPolicy::Overwrite => {
+                        Resolution::Skip(format!("{:?} is moved to the same destination", occupant))
+                    }
+                    // the source is kept anyway, nothing to drop
+                    ConflictPolicy::Dedupe if planned.action != Action::Move => {
+                        Resolution::Skip(format!("destination is taken by {:?}", occupant))
+                    }
+                    ConflictPolicy::Dedupe => match same_content(&planned.source, &occupant) {
+                        Ok(true) => Resolution::Dedupe,
+                        Ok(false) => Resolution::Skip(format!(
+                            "destination is taken by a different file {:?}",
+                            occupant
+                        )),
+                        Err(e) => Resolution::Skip(e.to_string()),
+                    },
+                };
+            }
+
+            match planned.resolution {
+                Resolution::Move | Resolution::Rename | Resolution::Overwrite => {
+                    taken.insert(planned.destination.clone(), planned.source.clone());
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// A plan saved with MovePlan::save, checked again as if it was just made:
+    /// the files must be in the root and unchanged, and the conflicts are solved
+    /// again since the destinations may be taken now
+    pub fn load_plan<P: AsRef<Path>>(&self, path: P) -> Result<MovePlan, FOError> {
+        let mut plan = MovePlan::load(path)?;
+        for planned in plan.moves.iter_mut() {
+            let outside = [&planned.source, &planned.destination]
+                .into_iter()
+                .find(|path| !self.is_inside(path));
+            if let Some(outside) = outside {
+                planned.resolution = Resolution::Skip(format!("{:?} is outside of root", outside));
+                continue;
+            }
+            match FileHelper::new(&planned.source).state() {
+                Ok(state) if state == planned.state => (),
+                _ => {
+                    planned.resolution =
+                        Resolution::Skip("file changed since it was planned".to_owned());
+                    continue;
+                }
+            }
+            // the skips chosen in the review stay
+            if !matches!(planned.resolution, Resolution::Skip(_)) {
+                planned.resolution = Resolution::Move;
+            }
+        }
+        self.resolve_conflicts(&mut plan.moves, &mut HashMap::new());
+        Ok(plan)
+    }
+
+    /// under the root, without `..` to get out of it
+    fn is_inside(&self, path: &Path) -> bool {
+        path.strip_prefix(&self.root).is_ok_and(|relative| {
+            relative
+                .components()
+                .all(|c| matches!(c, Component::Normal(_)))
+        })
+    }
+
+    /// Plan one file again, after its meta.yaml changed
+    pub fn plan_one<P: AsRef<Path>>(&self, path: P) -> Result<PlannedMove, FOError> {
+        self.plan_file(&FileHelper::new(path))
+    }
+
+    /// Solve the conflicts of the whole plan again, after some moves changed
+    pub fn resolve(&self, plan: &mut MovePlan) {
+        self.resolve_conflicts(&mut plan.moves, &mut HashMap::new());
+    }
+
+    fn plan_file(&self, file: &FileHelper) -> Result<PlannedMove, FOError> {
+        let meta = file.read_meta()?;
+        if meta.ignore {
+            return Err(FOError::MoveError("ignored in its meta.yaml".to_owned()));
+        }
+        let route = Mover::new(file.get_path())
+            .with_meta(meta)
+            .get_route(self.config, self.schemalist)?;
+        let relative = if route.path.is_empty() {
+            PathBuf::from(UNCATEGORIZED).join(file.file_name())
+        } else {
+            PathBuf::from(&route.path)
+        };
+
+        // field value come from filename, make sure it can't get out of root
+        if relative
+            .components()
+            .any(|c| !matches!(c, Component::Normal(_)))
+        {
+            return Err(FOError::MoveError(format!(
+                "destination {:?} is outside of root",
+                relative
+            )));
+        }
+        let action = self
+            .action
+            .or_else(|| self.schema_setting(&route.tree.schemas(), |s| s.action))
+            .or(self.config.meta.other.action)
+            .unwrap_or_default();
+        Ok(PlannedMove {
+            source: file.get_path().to_owned(),
+            destination: self.root.join(relative),
+            pattern: route.pattern,
+            fields: route.fields,
+            tree: route.tree,
+            resolution: Resolution::Move,
+            action,
+            state: file.state()?,
+        })
+    }
+
+    /// Check every move of the plan without touching anything
+    pub fn dry_run(&self, plan: &MovePlan) -> Vec<(PlannedMove, MoveOutcome)> {
+        plan.moves
+            .iter()
+            .map(|planned| (planned.clone(), move_file(planned, false)))
+            .collect()
+    }
+
+    /// Run every move of the plan, each successful move is recorded in the journal.
+    /// a move that can't be recorded is put back, undo couldn't do it
+    pub async fn apply(
+        &self,
+        plan: &MovePlan,
+        journal: &Journal<'_>,
+    ) -> Vec<(PlannedMove, MoveOutcome)> {
+        let mut result = vec![];
+        for planned in &plan.moves {
+            let mut outcome = move_file(planned, true);
+            if let MoveOutcome::Moved | MoveOutcome::Removed = outcome {
+                if let Err(e) = journal.record(planned).await {
+                    outcome = MoveOutcome::Failed(match unplace(&self.root, planned) {
+                        Ok(_) => e,
+                        Err(back) => FOError::MoveError(format!(
+                            "{}, and it could not be put back: {}",
+                            e, back
+                        )),
+                    });
+                }
+            }
+            result.push((planned.clone(), outcome));
+        }
+        result
+    }
+}
+
+/// A folder to organize, with the config it inherit
+pub struct Folder {
+    pub path: PathBuf,
+    /// where destinations start from, the closest folder with a config of its own
+    pub library: PathBuf,
+    pub config: Config,
+}
+
+/// config files that make a folder a library of its own
+const FOLDER_CONFIGS: [&str; 3] = ["_data.yaml", "_schema.yaml", "_meta.yaml"];
+
+/// The root and every folder under it, parents first.
+/// Hidden folders and symlinks to folders are not followed
+pub fn walk<P: AsRef<Path>>(root: P, config: &Config) -> Result<Vec<Folder>, FOError> {
+    let root = root.as_ref().to_owned();
+    let top = root.clone();
+    let mut folders = vec![];
+    let mut stack = vec![Folder {
+        path: root.clone(),
+        library: root,
+        config: config.clone(),
+    }];
+    while let Some(folder) = stack.pop() {
+        let mut children = vec![];
+        for entry in fs::read_dir(&folder.path)? {
+            let path = entry?.path();
+            let hidden = path
+                .file_name()
+                .is_some_and(|name| name.to_string_lossy().starts_with('.'));
+            let view = folder.path == top && path.ends_with(VIEWS_FOLDER);
+            if hidden || view || !fs::symlink_metadata(&path)?.is_dir() {
+                continue;
+            }
+
+            let mut config = folder.config.clone();
+            config.combine_config(&FileHelper::new(&path).read_config()?, true);
+            let own_config = path.with_extension("yaml").is_file()
+                || FOLDER_CONFIGS.iter().any(|name| path.join(name).is_file());
+            children.push(Folder {
+                library: match own_config {
+                    true => path.clone(),
+                    false => folder.library.clone(),
+                },
+                path,
+                config,
+            });
+        }
+        // pop in name order
+        children.sort_by(|a, b| b.path.cmp(&a.path));
+        stack.extend(children);
+        folders.push(folder);
+    }
+    Ok(folders)
+}
+
+fn unfinished() -> FOError {
+    FOError::MoveError("file is still being written".to_owned())
+}
+
+/// print every outcome, then the summary
+pub fn report(plan: &MovePlan, result: &[(PlannedMove, MoveOutcome)]) -> Summary {
+    for (path, e) in &plan.errors {
+        println!("{:?}: ignored ({})", path, e);
+    }
+    let mut summary = Summary::default();
+    for (planned, outcome) in result {
+        println!(
+            "{:?} -> {:?} ({}): {}",
+            planned.source,
+            planned.destination,
+            planned.action.as_str(),
+            outcome
+        );
+        summary.add(outcome);
+    }
+    println!("{}, {} ignored", summary, plan.errors.len());
+    summary
+}
+
+fn move_file(planned: &PlannedMove, apply: bool) -> MoveOutcome {
+    if planned.source == planned.destination {
+        return MoveOutcome::Skipped("already in place".to_owned());
+    }
+    match FileHelper::new(&planned.source).state() {
+        Ok(state) if state == planned.state => (),
+        Ok(_) => return MoveOutcome::Skipped("file changed since it was planned".to_owned()),
+        Err(e) => return MoveOutcome::Failed(e),
+    }
+    match &planned.resolution {
+        Resolution::Skip(reason) => return MoveOutcome::Skipped(reason.to_owned()),
+        Resolution::Move | Resolution::Rename if planned.destination.exists() => {
+            return MoveOutcome::Failed(FOError::MoveError(
+                "destination already exists".to_owned(),
+            ));
+        }
+        _ => (),
+    }
+    if !apply {
+        return MoveOutcome::Planned;
+    }
+
+    // check again, the file it's compared with may not be moved
+    if let Resolution::Dedupe = planned.resolution {
+        return match same_content(&planned.source, &planned.destination) {
+            Ok(true) => match fs::remove_file(&planned.source) {
+                Ok(_) => MoveOutcome::Removed,
+                Err(e) => MoveOutcome::Failed(e.into()),
+            },
+            Ok(false) => MoveOutcome::Failed(FOError::MoveError(
+                "destination is not identical anymore".to_owned(),
+            )),
+            Err(e) => MoveOutcome::Failed(e),
+        };
+    }
+
+    match place(planned) {
+        Ok(_) => MoveOutcome::Moved,
+        Err(e) => MoveOutcome::Failed(e),
+    }
+}
+
+fn place(planned: &PlannedMove) -> Result<(), FOError> {
+    let (source, destination) = (&planned.source, &planned.destination);
+    if let Some(parent) = destination.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // rename and copy replace the file, links don't
+    if planned.resolution == Resolution::Overwrite
+        && !matches!(planned.action, Action::Move | Action::Copy)
+    {
+        fs::remove_file(destination)?;
+    }
+
+    match planned.action {
+        Action::Move => {
+            fs::rename(source, destination)?;
+            // the choices stay with the file
+            let meta = FileHelper::new(source).meta_path();
+            if meta.exists() {
+                fs::rename(meta, FileHelper::new(destination).meta_path())?;
+            }
+        }
+        Action::Copy => {
+            fs::copy(source, destination)?;
+        }
+        Action::Hardlink => fs::hard_link(source, destination)?,
+        Action::Symlink => symlink(fs::canonicalize(source)?, destination)?,
+        Action::RelativeSymlink => {
+            let folder = fs::canonicalize(destination.parent().unwrap_or(Path::new(".")))?;
+            symlink(relative_to(fs::canonicalize(source)?, folder), destination)?
+        }
+    }
+    Ok(())
+}
+
+/// the opposite of place, or of the removal of a dedupe. an overwritten file
+/// is not brought back
+fn unplace(root: &Path, planned: &PlannedMove) -> Result<(), FOError> {
+    let (source, destination) = (&planned.source, &planned.destination);
+    if let Resolution::Dedupe = planned.resolution {
+        fs::copy(destination, source)?;
+        return Ok(());
+    }
+    match planned.action {
+        Action::Move => {
+            fs::rename(destination, source)?;
+            let meta = FileHelper::new(destination).meta_path();
+            if meta.exists() {
+                fs::rename(meta, FileHelper::new(source).meta_path())?;
+            }
+        }
+        _ => fs::remove_file(destination)?,
+    }
+    remove_empty_parents(root, destination);
+    Ok(())
+}
+
+#[cfg(unix)]
+pub fn symlink<P: AsRef<Path>, Q: AsRef<Path>>(original: P, link: Q) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(original, link)
+}
+
+#[cfg(windows)]
+pub fn symlink<P: AsRef<Path>, Q: AsRef<Path>>(original: P, link: Q) -> std::io::Result<()> {
+    std::os::windows::fs::symlink_file(original, link)
+}
+
+pub fn same_content(a: &Path, b: &Path) -> Result<bool, FOError> {
+    let (a, b) = (FileHelper::new(a), FileHelper::new(b));
+    if a.state()?.size != b.state()?.size {
+        return Ok(false);
+    }
+    Ok(a.md5()? == b.md5()?)
+}
+
+/// name (1).ext, name (2).ext, ... until one is free, on the disk and in taken
+pub(crate) fn free_name(path: &Path, taken: &HashMap<PathBuf, PathBuf>) -> PathBuf {
+    let stem = path
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let ext = path
+        .extension()
+        .map(|e| format!(".{}", e.to_string_lossy()))
+        .unwrap_or_default();
+    let mut i = 1;
+    loop {
+        let candidate = path.with_file_name(format!("{} ({}){}", stem, i, ext));
+        if !candidate.exists() && !taken.contains_key(&candidate) {
+            return candidate;
+        }
+        i += 1;
+    }
+}
+
+#[async_std::test]
+async fn test_organize() {
+    let root = crate::helper::test_util::TestRoot::new("organize");
+    fs::write(root.join("naruto-01.mp4"), "a").unwrap();
+    fs::write(root.join("readme.txt"), "b").unwrap();
+    fs::write(
+        root.join("_data.yaml"),
+        r#"
+        _meta:
+            settle: 0
+            children: anime
+        _schema:
+            anime:
+                filename: '%name%'
+                children: file
+                fields: name
+            file:
+                filename: '%filename%.%ext%'
+                fields: filename, ext
+        _import:
+            - "{?}-{?}.{mp4|mp3}": name, filename, ext
+        "#,
+    )
+    .unwrap();
+
+    let config = FileHelper::new(&root).read_config().unwrap();
+    let sl = SchemaList::from(&config.schema);
+    let organizer = Organizer::new(&root, &config, &sl);
+    let plan = organizer.plan().await.unwrap();
+    assert_eq!(plan.moves.len(), 1);
+    assert_eq!(plan.errors.len(), 1);
+    assert_eq!(plan.moves[0].destination, root.join("naruto/01.mp4"));
+
+    // dry run doesn't touch anything
+    let result = organizer.dry_run(&plan);
+    assert!(matches!(result[0].1, MoveOutcome::Planned));
+    assert!(root.join("naruto-01.mp4").exists());
+
+    let db = root.db().await;
+    let journal = Journal::start(&db).await.unwrap();
+    let result = organizer.apply(&plan, &journal).await;
+    assert!(matches!(result[0].1, MoveOutcome::Moved));
+    assert!(root.join("naruto/01.mp4").exists());
+    assert!(!root.join("naruto-01.mp4").exists());
+    let entries = db.journal(journal.run_id).await.unwrap();
+    assert_eq!(entries[0].source, "./naruto-01.mp4");
+    assert_eq!(entries[0].destination, "./naruto/01.mp4");
+    assert_eq!(entries[0].schema, "anime/file");
+}
+
+#[async_std::test]
+async fn test_conflict() {
+    let root = crate::helper::test_util::TestRoot::new("conflict");
+    fs::create_dir_all(root.join("a")).unwrap();
+    fs::write(root.join("a/1.txt"), "same").unwrap();
+    fs::write(root.join("a-1.txt"), "same").unwrap();
+    fs::write(root.join("a_1.txt"), "different").unwrap();
+    let config = serde_yaml::from_str::<Config>(
+        r#"
+        _meta:
+            settle: 0
+            children: file
+        _schema:
+            file:
+                filename: '%group%/%name%.txt'
+                fields: group, name
+        _import:
+            - "{?}{-|_}{?}.txt": group, _, name
+        "#,
+    )
+    .unwrap();
+    let sl = SchemaList::from(&config.schema);
+
+    let plan = Organizer::new(&root, &config, &sl).plan().await.unwrap();
+    assert!(plan
+        .moves
+        .iter()
+        .all(|m| matches!(m.resolution, Resolution::Skip(_))));
+
+    let organizer = Organizer::new(&root, &config, &sl).with_conflict(Some(ConflictPolicy::Rename));
+    let plan = organizer.plan().await.unwrap();
+    assert_eq!(plan.moves[0].destination, root.join("a/1 (1).txt"));
+    assert_eq!(plan.moves[1].destination, root.join("a/1 (2).txt"));
+
+    let organizer = Organizer::new(&root, &config, &sl).with_conflict(Some(ConflictPolicy::Dedupe));
+    let plan = organizer.plan().await.unwrap();
+    assert_eq!(plan.moves[0].resolution, Resolution::Dedupe);
+    assert!(matches!(plan.moves[1].resolution, Resolution::Skip(_)));
+}
+
+#[async_std::test]
+async fn test_action() {
+    use crate::journal::undo;
+
+    let root = crate::helper::test_util::TestRoot::new("action");
+    fs::write(root.join("a-1.txt"), "a").unwrap();
+    fs::write(root.join("b-2.txt"), "b").unwrap();
+    let config = serde_yaml::from_str::<Config>(
+        r#"
+        _meta:
+            settle: 0
+            children: file
+        _schema:
+            file:
+                filename: '%group%/%name%.txt'
+                fields: group, name
+                action: relative-symlink
+        _import:
+            - "{?}-{?}.txt": group, name
+        "#,
+    )
+    .unwrap();
+    let sl = SchemaList::from(&config.schema);
+    let db = root.db().await;
+    let journal = Journal::start(&db).await.unwrap();
+    let organizer = Organizer::new(&root, &config, &sl);
+    organizer
+        .apply(&organizer.plan().await.unwrap(), &journal)
+        .await;
+
+    assert!(root.join("a-1.txt").exists());
+    assert_eq!(
+        fs::read_link(root.join("a/1.txt")).unwrap(),
+        PathBuf::from("../a-1.txt")
+    );
+    let id = db.find_path("./a/1.txt").await.unwrap();
+    assert!(id.is_some());
+
+    undo(&db, journal.run_id).await.unwrap();
+    assert!(!root.join("a").exists());
+    assert!(root.join("a-1.txt").exists());
+    assert_eq!(db.find_path("./a/1.txt").await.unwrap(), None);
+}
+
+#[async_std::test]
+async fn test_unrecorded() {
+    let root = crate::helper::test_util::TestRoot::new("unrecorded");
+    fs::write(root.join("a-1.txt"), "a").unwrap();
+    let config = serde_yaml::from_str::<Config>(
+        r#"
+        _meta:
+            settle: 0
+            children: file
+        _schema:
+            file:
+                filename: '%group%/%name%.txt'
+                fields: group, name
+        _import:
+            - "{?}-{?}.txt": group, name
+        "#,
+    )
+    .unwrap();
+    let sl = SchemaList::from(&config.schema);
+    let db = root.db().await;
+    let journal = Journal::start(&db).await.unwrap();
+    // the journal can't be written, undo would never find the move
+    let pool = sqlx::SqlitePool::connect(&format!("sqlite://{}", root.join("fo.db").display()))
+        .await
+        .unwrap();
+    sqlx::query("DROP TABLE journal")
+        .execute(&pool)
+        .await
+        .unwrap();
+    let organizer = Organizer::new(&root, &config, &sl);
+    let result = organizer
+        .apply(&organizer.plan().await.unwrap(), &journal)
+        .await;
+    assert!(matches!(result[0].1, MoveOutcome::Failed(_)));
+    assert!(root.join("a-1.txt").exists());
+    assert!(!root.join("a").exists());
+}
+
+#[async_std::test]
+async fn test_saved_plan() {
+    let root = crate::helper::test_util::TestRoot::new("saved-plan");
+    fs::write(root.join("a-1.txt"), "a").unwrap();
+    fs::write(root.join("b-2.txt"), "b").unwrap();
+    let config = serde_yaml::from_str::<Config>(
+        r#"
+        _meta:
+            settle: 0
+            children: file
+        _schema:
+            file:
+                filename: '%group%/%name%.txt'
+                fields: group, name
+        _import:
+            - "{?}-{?}.txt": group, name
+        "#,
+    )
+    .unwrap();
+    let sl = SchemaList::from(&config.schema);
+    let organizer = Organizer::new(&root, &config, &sl);
+    organizer
+        .plan()
+        .await
+        .unwrap()
+        .save(root.join("plan.json"))
+        .unwrap();
+
+    // changed after the review
+    fs::write(root.join("b-2.txt"), "changed").unwrap();
+
+    let plan = organizer.load_plan(root.join("plan.json")).unwrap();
+    assert_eq!(
+        plan.moves[0].fields[0],
+        ("group".to_owned(), "a".to_owned())
+    );
+    assert_eq!(plan.moves[0].schemas(), vec!["file"]);
+    let db = root.db().await;
+    let journal = Journal::start(&db).await.unwrap();
+    let result = organizer.apply(&plan, &journal).await;
+    assert!(matches!(result[0].1, MoveOutcome::Moved));
+    assert!(matches!(result[1].1, MoveOutcome::Skipped(_)));
+    assert!(root.join("b-2.txt").exists());
+
+    // edited by hand to get out of the root, or taken since it was saved
+    fs::write(root.join("c-3.txt"), "c").unwrap();
+    fs::write(root.join("d-4.txt"), "d").unwrap();
+    let mut plan = organizer.plan().await.unwrap();
+    let c = plan
+        .moves
+        .iter()
+        .position(|planned| planned.source.ends_with("c-3.txt"))
+        .unwrap();
+    plan.moves[c].destination = root.join("../escaped.txt");
+    plan.save(root.join("plan.json")).unwrap();
+    fs::create_dir(root.join("d")).unwrap();
+    fs::write(root.join("d/4.txt"), "taken").unwrap();
+    let plan = organizer.load_plan(root.join("plan.json")).unwrap();
+    let result = organizer.apply(&plan, &journal).await;
+    let outcome = |name: &str| {
+        &result
+            .iter()
+            .find(|(planned, _)| planned.source.ends_with(name))
+            .unwrap()
+            .1
+    };
+    assert!(matches!(outcome("b-2.txt"), MoveOutcome::Moved));
+    assert!(matches!(outcome("c-3.txt"), MoveOutcome::Skipped(_)));
+    assert!(matches!(outcome("d-4.txt"), MoveOutcome::Skipped(_)));
+    assert!(root.join("c-3.txt").exists());
+    assert!(!root.join("../escaped.txt").exists());
+    assert_eq!(fs::read_to_string(root.join("d/4.txt")).unwrap(), "taken");
+}
+
+#[async_std::test]
+async fn test_recursive() {
+    let root = crate::helper::test_util::TestRoot::new("recursive");
+    for folder in ["books", "misc", ".hidden"] {
+        fs::create_dir_all(root.join(folder)).unwrap();
+    }
+    fs::write(root.join("misc/naruto-02.mp4"), "a").unwrap();
+    fs::write(root.join("books/math-1.pdf"), "b").unwrap();
+    fs::write(root.join("books/naruto-03.mp4"), "c").unwrap();
+    fs::write(root.join(".hidden/naruto-04.mp4"), "d").unwrap();
+    fs::write(
+        root.join("_data.yaml"),
+        r#"
+        _meta:
+            settle: 0
+            children: anime
+        _schema:
+            anime:
+                filename: '%name%'
+                children: file
+                fields: name
+            file:
+                filename: '%filename%.%ext%'
+                fields: filename, ext
+        _import:
+            - "{?}-{?}.{mp4|mp3}": name, filename, ext
+        "#,
+    )
+    .unwrap();
+    // the sub-library only add what it need
+    fs::write(
+        root.join("books/_schema.yaml"),
+        r#"
+        book:
+            filename: '%title%/%page%.%ext%'
+            fields: title!, page(num), ext
+        "#,
+    )
+    .unwrap();
+    fs::write(root.join("books/_meta.yaml"), "children: book").unwrap();
+    fs::write(
+        root.join("books/_data.yaml"),
+        r#"
+        _import:
+            - "{?}-{?}.{pdf}": title, page, ext
+        "#,
+    )
+    .unwrap();
+
+    let config = FileHelper::new(&root).read_config().unwrap();
+    let sl = SchemaList::from(&config.schema);
+    let organizer = Organizer::new(&root, &config, &sl);
+    assert!(organizer.plan().await.unwrap().moves.is_empty());
+
+    let plan = organizer.plan_recursive().await.unwrap();
+    let moves = plan
+        .moves
+        .iter()
+        .map(|planned| (planned.source.clone(), planned.destination.clone()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        moves,
+        vec![
+            (root.join("books/math-1.pdf"), root.join("books/math/1.pdf")),
+            (
+                root.join("books/naruto-03.mp4"),
+                root.join("books/naruto/03.mp4")
+            ),
+            (root.join("misc/naruto-02.mp4"), root.join("naruto/02.mp4")),
+        ]
+    );
+}
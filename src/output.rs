@@ -0,0 +1,161 @@
+// How search results are printed
+// plain and nul are only the paths, for mpv or xargs -0.
+// jsonl is one object per file with every indexed field, for jq.
+// csv has a header and the columns asked for, a column is path, name,
+// modified or any field (more than one value are joined with ", ").
+
+use std::{collections::BTreeMap, io::Write};
+
+use serde_json::{json, Value};
+
+use crate::{
+    db::{IndexDB, SearchHit},
+    error::FOError,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// one path per line
+    #[default]
+    Plain,
+    /// paths ended by a NUL byte
+    Nul,
+    /// one json object per line
+    Jsonl,
+    /// a header then one row per file
+    Csv,
+}
+
+/// Print the hits, absolute join the db folder to the paths
+pub async fn write_hits<W: Write, T: AsRef<str>>(
+    db: &IndexDB,
+    hits: &[SearchHit],
+    format: OutputFormat,
+    columns: &[T],
+    absolute: bool,
+    output: &mut W,
+) -> Result<(), FOError> {
+    let path = |hit: &SearchHit| match absolute {
+        true => db.absolute(&hit.path).to_string_lossy().into_owned(),
+        false => hit.path.to_owned(),
+    };
+    match format {
+        OutputFormat::Plain => {
+            for hit in hits {
+                writeln!(output, "{}", path(hit))?;
+            }
+        }
+        OutputFormat::Nul => {
+            for hit in hits {
+                write!(output, "{}\0", path(hit))?;
+            }
+        }
+        OutputFormat::Jsonl => {
+            for hit in hits {
+                let fields = json_fields(db.fields(hit.id).await?);
+                let line = json!({
+                    "path": path(hit),
+                    "name": hit.name,
+                    "modified": hit.last_modified.to_rfc3339(),
+                    "folder": hit.is_folder,
+                    "fields": fields,
+                });
+                writeln!(output, "{}", line)?;
+            }
+        }
+        OutputFormat::Csv => {
+            let header = columns.iter().map(|c| csv_cell(c.as_ref()));
+            writeln!(output, "{}", header.collect::<Vec<_>>().join(","))?;
+            for hit in hits {
+                let fields = db.fields(hit.id).await?;
+                let row = columns.iter().map(|column| {
+                    let cell = match column.as_ref().to_lowercase().as_str() {
+                        "path" => path(hit),
+                        "name" => hit.name.to_owned(),
+                        "modified" => hit.last_modified.to_rfc3339(),
+                        column => fields
+                            .iter()
+                            .filter(|(field, _)| field.eq_ignore_ascii_case(column))
+                            .map(|(_, value)| value.as_str())
+                            .collect::<Vec<_>>()
+                            .join(", "),
+                    };
+                    csv_cell(&cell)
+                });
+                writeln!(output, "{}", row.collect::<Vec<_>>().join(","))?;
+            }
+        }
+    }
+    output.flush()?;
+    Ok(())
+}
+
+/// a field with more than one value is an array
+fn json_fields(fields: Vec<(String, String)>) -> BTreeMap<String, Value> {
+    let mut map: BTreeMap<String, Value> = BTreeMap::new();
+    for (field, value) in fields {
+        match map.get_mut(&field) {
+            Some(Value::Array(values)) => values.push(Value::String(value)),
+            Some(first) => *first = json!([first.take(), value]),
+            None => {
+                map.insert(field, Value::String(value));
+            }
+        }
+    }
+    map
+}
+
+/// quoted if it has a comma, a quote or a new line
+fn csv_cell(value: &str) -> String {
+    match value.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", value.replace('"', "\"\"")),
+        false => value.to_owned(),
+    }
+}
+
+#[async_std::test]
+async fn test_write_hits() {
+    use crate::{schema::SchemaList, search::Operation};
+
+    let root = crate::helper::test_util::TestRoot::new("output");
+    let db = root.db().await;
+    let id = db.add_file("a, \"b\".mkv", 0).await.unwrap();
+    let fields = [("tags", "x"), ("tags", "y"), ("name", "a")]
+        .map(|(field, value)| (field.to_owned(), value.to_owned()));
+    let mut batch = db.batch().await.unwrap();
+    batch.set_fields(id, &fields).await.unwrap();
+    batch.commit().await.unwrap();
+    db.add_file("c.mkv", 0).await.unwrap();
+
+    let operation = Operation::parse("name: mkv").unwrap();
+    let hits = db
+        .search(&operation, &SchemaList::new(), true)
+        .await
+        .unwrap();
+    let print = |format, absolute| {
+        let (db, hits) = (&db, &hits);
+        async move {
+            let mut output = vec![];
+            write_hits(db, hits, format, &["name", "tags"], absolute, &mut output)
+                .await
+                .unwrap();
+            String::from_utf8(output).unwrap()
+        }
+    };
+
+    assert_eq!(
+        print(OutputFormat::Nul, false).await,
+        "a, \"b\".mkv\0c.mkv\0"
+    );
+    assert_eq!(
+        print(OutputFormat::Csv, false).await,
+        "name,tags\n\"a, \"\"b\"\".mkv\",\"x, y\"\nc.mkv,\n"
+    );
+    let jsonl = print(OutputFormat::Jsonl, true).await;
+    let first: Value = serde_json::from_str(jsonl.lines().next().unwrap()).unwrap();
+    assert_eq!(first["fields"], json!({"name": "a", "tags": ["x", "y"]}));
+    assert_eq!(
+        first["path"],
+        root.join("a, \"b\".mkv").to_string_lossy().as_ref()
+    );
+}
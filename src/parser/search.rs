@@ -0,0 +1,127 @@
+use nom::{
+    branch::alt,
+    bytes::complete::{tag, take_till, take_while1},
+    character::complete::{multispace0, none_of, one_of},
+    combinator::{all_consuming, map, not, opt, recognize, verify},
+    multi::{many0, many1, separated_list1},
+    sequence::{delimited, pair, preceded, separated_pair, terminated},
+    IResult,
+};
+
+use crate::search::{Bound, Operation};
+
+/// `field:`, dot is allowed for `schema.field`
+pub fn field_name(input: &str) -> IResult<&str, &str> {
+    terminated(
+        take_while1(|c: char| c.is_alphanumeric() || c == '_' || c == '.'),
+        preceded(multispace0, tag(":")),
+    )(input)
+}
+
+/// "quoted text" or a word, a word can't be a field name
+pub fn value(input: &str) -> IResult<&str, Operation> {
+    let quoted = delimited(tag("\""), take_till(|c| c == '"'), tag("\""));
+    let word = preceded(
+        pair(not(field_name), not(one_of("-!"))),
+        take_while1(|c: char| !c.is_whitespace() && !"()|\"".contains(c)),
+    );
+    map(alt((quoted, word)), |v: &str| {
+        Operation::Value(v.to_owned())
+    })(input)
+}
+
+/// one end of a range, until a space or `..`
+fn bound(input: &str) -> IResult<&str, Bound> {
+    map(
+        recognize(many1(preceded(not(tag("..")), none_of(" \t\r\n()|\"")))),
+        |value: &str| Bound {
+            value: value.to_owned(),
+            inclusive: true,
+        },
+    )(input)
+}
+
+/// `>a`, `>=a`, `<a`, `<=a`, `a..b`, `a..` or `..b`
+pub fn range(input: &str) -> IResult<&str, Operation> {
+    let compare = pair(alt((tag(">="), tag("<="), tag(">"), tag("<"))), bound);
+    let between = verify(
+        separated_pair(opt(bound), tag(".."), opt(bound)),
+        |(min, max)| min.is_some() || max.is_some(),
+    );
+    alt((
+        map(compare, |(op, mut bound)| {
+            bound.inclusive = op.ends_with('=');
+            match op.starts_with('>') {
+                true => Operation::Range(Some(bound), None),
+                false => Operation::Range(None, Some(bound)),
+            }
+        }),
+        map(between, |(min, max)| Operation::Range(min, max)),
+    ))(input)
+}
+
+/// `-a`, `!a` or `(a b)`
+fn not_or_group<'a, F>(inner: F) -> impl FnMut(&'a str) -> IResult<&'a str, Operation>
+where
+    F: FnMut(&'a str) -> IResult<&'a str, Operation> + Copy,
+{
+    move |input| {
+        alt((
+            map(preceded(one_of("-!"), not_or_group(inner)), |op| {
+                Operation::Not(Box::new(op))
+            }),
+            delimited(
+                pair(tag("("), multispace0),
+                query,
+                pair(multispace0, tag(")")),
+            ),
+            inner,
+        ))(input)
+    }
+}
+
+/// a value, a field with its values, or one of those negated or grouped
+fn unary(input: &str) -> IResult<&str, Operation> {
+    not_or_group(|input| alt((field, value))(input))(input)
+}
+
+/// values after `field:` until the next field
+fn field(input: &str) -> IResult<&str, Operation> {
+    let field_value = |input| not_or_group(|input| alt((range, value))(input))(input);
+    map(
+        pair(
+            field_name,
+            many0(preceded(multispace0, or_list(field_value))),
+        ),
+        |(name, values)| Operation::Field(name.to_owned(), values),
+    )(input)
+}
+
+/// `a | b | c`
+fn or_list<'a, F>(inner: F) -> impl FnMut(&'a str) -> IResult<&'a str, Operation>
+where
+    F: FnMut(&'a str) -> IResult<&'a str, Operation>,
+{
+    map(
+        separated_list1(delimited(multispace0, tag("|"), multispace0), inner),
+        |mut list| match list.len() {
+            1 => list.remove(0),
+            _ => Operation::Or(list),
+        },
+    )
+}
+
+/// `a b | c`, or binds tighter than and
+pub fn query(input: &str) -> IResult<&str, Operation> {
+    map(
+        many1(preceded(multispace0, or_list(unary))),
+        |mut list| match list.len() {
+            1 => list.remove(0),
+            _ => Operation::And(list),
+        },
+    )(input)
+}
+
+pub fn full_query(input: &str) -> IResult<&str, Operation> {
+    all_consuming(terminated(query, multispace0))(input)
+}
@@ -0,0 +1,216 @@
+// Interactive review of an organize plan
+// every move is shown one by one, and can be accepted, skipped or changed.
+// a change is saved in the file's meta.yaml then the file is planned again,
+// so the next run will do the same thing without asking. a skip is only for
+// this run, ignoring the file in the next ones too is its own answer.
+
+use std::io::{BufRead, Write};
+
+use crate::{
+    config_reader::FileMeta,
+    error::FOError,
+    helper::FileHelper,
+    organizer::{MovePlan, Organizer, PlannedMove},
+};
+
+const HELP: &str =
+    "[a]ccept, [s]kip this time, [i]gnore forever, [d]estination, [f]ield, [p]in schema, [q]uit";
+
+/// Ask about every move of the plan, the moves that are not accepted are dropped
+pub fn review<R: BufRead, W: Write>(
+    organizer: &Organizer,
+    plan: MovePlan,
+    input: &mut R,
+    output: &mut W,
+) -> Result<MovePlan, FOError> {
+    let mut reviewed = MovePlan {
+        moves: vec![],
+        errors: plan.errors,
+    };
+
+    'moves: for mut planned in plan.moves {
+        if planned.source == planned.destination {
+            reviewed.moves.push(planned);
+            continue;
+        }
+        loop {
+            show(&planned, output)?;
+            let Some(answer) = ask(HELP, input, output)? else {
+                break 'moves;
+            };
+            let file = FileHelper::new(&planned.source);
+            let mut meta = file.read_meta()?;
+            match answer.as_str() {
+                "a" | "" => {
+                    reviewed.moves.push(planned);
+                    break;
+                }
+                "s" => break,
+                "i" => {
+                    meta.ignore = true;
+                    file.write_meta(&meta)?;
+                    break;
+                }
+                "q" => break 'moves,
+                "d" => {
+                    let Some(destination) = ask("destination", input, output)? else {
+                        break 'moves;
+                    };
+                    meta.destination = Some(destination);
+                }
+                "f" => {
+                    let Some(field) = ask("field=value", input, output)? else {
+                        break 'moves;
+                    };
+                    let Some((field, value)) = field.split_once('=') else {
+                        writeln!(output, "expected field=value")?;
+                        continue;
+                    };
+                    meta.fields
+                        .insert(field.trim().to_owned(), value.trim().to_owned());
+                }
+                "p" => {
+                    let Some(schema) = ask("schema", input, output)? else {
+                        break 'moves;
+                    };
+                    meta.schema = Some(schema);
+                }
+                _ => {
+                    writeln!(output, "{}", HELP)?;
+                    continue;
+                }
+            }
+            planned = replan(organizer, planned, &file, meta, output)?;
+        }
+    }
+
+    organizer.resolve(&mut reviewed);
+    Ok(reviewed)
+}
+
+/// plan the file with its new meta, the old meta is put back if it doesn't work
+fn replan<W: Write>(
+    organizer: &Organizer,
+    planned: PlannedMove,
+    file: &FileHelper,
+    meta: FileMeta,
+    output: &mut W,
+) -> Result<PlannedMove, FOError> {
+    let old_meta = file.read_meta()?;
+    file.write_meta(&meta)?;
+    match organizer.plan_one(&planned.source) {
+        Ok(replanned) => Ok(replanned),
+        Err(e) => {
+            writeln!(output, "can't use it: {}", e)?;
+            file.write_meta(&old_meta)?;
+            Ok(planned)
+        }
+    }
+}
+
+fn show<W: Write>(planned: &PlannedMove, output: &mut W) -> Result<(), FOError> {
+    writeln!(output, "{:?} -> {:?}", planned.source, planned.destination)?;
+    let fields = planned
+        .fields
+        .iter()
+        .map(|(field, value)| format!("{}={}", field, value))
+        .collect::<Vec<_>>();
+    writeln!(output, "  fields: {}", fields.join(", "))?;
+    writeln!(output, "  schema: {}", planned.schemas().join("/"))?;
+    Ok(())
+}
+
+/// None when the input ended
+fn ask<R: BufRead, W: Write>(
+    question: &str,
+    input: &mut R,
+    output: &mut W,
+) -> Result<Option<String>, FOError> {
+    write!(output, "{}: ", question)?;
+    output.flush()?;
+    let mut answer = String::new();
+    if input.read_line(&mut answer)? == 0 {
+        return Ok(None);
+    }
+    Ok(Some(answer.trim().to_owned()))
+}
+
+#[async_std::test]
+async fn test_review() {
+    use std::{fs, io::Cursor};
+
+    use crate::schema::SchemaList;
+
+    let root = crate::helper::test_util::TestRoot::new("review");
+    for name in [
+        "naruto-01.mp4",
+        "naruto-02.mp4",
+        "bleach-01.mp4",
+        "other-01.mp4",
+    ] {
+        fs::write(root.join(name), name).unwrap();
+    }
+    fs::write(
+        root.join("_data.yaml"),
+        r#"
+        _meta:
+            settle: 0
+            children: anime, movie
+        _schema:
+            anime:
+                filename: '%name%'
+                children: file
+                fields: name
+            movie:
+                filename: 'movie/%name%.%ext%'
+                fields: name, ext
+            file:
+                filename: '%filename%.%ext%'
+                fields: filename, ext
+        _import:
+            - "{?}-{?}.{mp4|mp3}": name, filename, ext
+        "#,
+    )
+    .unwrap();
+
+    let config = FileHelper::new(&root).read_config().unwrap();
+    let sl = SchemaList::from(&config.schema);
+    let organizer = Organizer::new(&root, &config, &sl);
+    let plan = organizer.plan().await.unwrap();
+    assert_eq!(plan.moves.len(), 4);
+
+    // bleach: edit a field, naruto-01: pin, naruto-02: skip, other: new destination
+    let mut input = Cursor::new("f\nname=Bleach\na\np\nmovie\na\ns\nd\nmisc/other.mp4\na\n");
+    let reviewed = review(&organizer, plan, &mut input, &mut vec![]).unwrap();
+    let destinations = |plan: &MovePlan| {
+        plan.moves
+            .iter()
+            .map(|planned| planned.destination.clone())
+            .collect::<Vec<_>>()
+    };
+    let expected = vec![
+        root.join("Bleach/01.mp4"),
+        root.join("movie/naruto.mp4"),
+        root.join("misc/other.mp4"),
+    ];
+    assert_eq!(destinations(&reviewed), expected);
+
+    // the next run agree without asking, and ask again about the skipped one
+    let plan = organizer.plan().await.unwrap();
+    assert_eq!(plan.moves[2].destination, root.join("naruto/02.mp4"));
+    assert!(plan.errors.is_empty());
+
+    // until it's ignored
+    let mut input = Cursor::new(
+        "a
+a
+i
+a
+",
+    );
+    let reviewed = review(&organizer, plan, &mut input, &mut vec![]).unwrap();
+    assert_eq!(destinations(&reviewed), expected);
+    let plan = organizer.plan().await.unwrap();
+    assert_eq!(destinations(&plan), expected);
+    assert_eq!(plan.errors.len(), 1);
+}
@@ -12,7 +12,10 @@ use std::{
 use regex::{Captures, Regex};
 
 use crate::{
-    config_reader::{CommaSeperated, ConfigDatatype, SchemaConfig, SchemaConfigItem},
+    config_reader::{
+        Action, CommaSeperated, ConfigDatatype, ConflictPolicy, Notation, SchemaConfig,
+        SchemaConfigItem,
+    },
     format::FormatString,
     helper::FieldHashMapBuilder,
 };
@@ -23,6 +26,9 @@ pub struct Schema {
     pub fields: HashMap<String, Field>,
     pub children: Vec<String>,
     pub filename: Option<String>,
+    pub conflict: Option<ConflictPolicy>,
+    pub action: Option<Action>,
+    pub notation: Option<Notation>,
 }
 
 impl Schema {
@@ -59,6 +65,14 @@ pub struct Field {
 }
 
 impl Field {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn datatype(&self) -> &ConfigDatatype {
+        &self.format
+    }
+
     pub fn to_format(&self) -> String {
         let mut string = String::from(&self.name);
         match self.format {
@@ -72,7 +86,8 @@ impl Field {
     }
 
     pub fn from_format(format: &str) -> Option<Self> {
-        let re = Regex::new(r"^(\w+)(?:\((\w+)\))?(!)?$").unwrap();
+        // `tags(str[])` too
+        let re = Regex::new(r"^(\w+)(?:\((\w+(?:\[\])?)\))?(!)?$").unwrap();
         let captures = re.captures(format)?;
         Some(Self {
             name: captures.get(1)?.as_str().to_string(),
@@ -131,32 +146,49 @@ impl Schema {
             fields: HashMap::new(),
             children: Vec::new(),
             filename: None,
+            conflict: None,
+            action: None,
+            notation: None,
         }
     }
 
-    pub fn is_fit(&self, data: &Vec<(String, String)>) -> bool {
+    /// Why the data doesn't fit the schema, None if it fit
+    pub fn fit_error(&self, data: &[(String, String)]) -> Option<String> {
         let data_map = data.iter().map(|d| (&d.0, &d.1)).collect::<HashMap<_, _>>();
         for (field_name, field_type) in &self.fields {
             // if data_map.get(field_name).is_none() && !field_type.forced {
             //     return false;
             // }
             match data_map.get(field_name) {
-                None if field_type.forced => return false,
+                None if field_type.forced => {
+                    return Some(format!("missing forced field {}", field_name))
+                }
                 None => continue,
                 Some(d) if !field_type.is_fit(field_name, d) => {
-                    return false;
+                    return Some(format!(
+                        "{} is \"{}\", expected {}",
+                        field_name,
+                        d,
+                        field_type.format.to_string()
+                    ));
                 }
                 _ => continue,
             }
         }
-        true
+        None
     }
 
     pub fn generate_string(&self, data: &Vec<(String, String)>) -> String {
         // let data_map = data.iter().map(|d| (&d.0, &d.1)).collect::<HashMap<_, _>>();
-        let data_map = FieldHashMapBuilder::new(&self.name).insert(&data).to_map();
-
-        let filename_formatter = FormatString::parse(&self.filename.as_ref().unwrap());
+        let data_map = FieldHashMapBuilder::new(&self.name)
+            .notation(self.notation.unwrap_or_default())
+            .insert(data)
+            .to_map();
+
+        let filename_formatter = match &self.filename {
+            Some(filename) => FormatString::parse(filename),
+            None => return String::new(),
+        };
         let vars = filename_formatter
             .vars
             .iter()
@@ -268,6 +300,9 @@ impl SchemaList {
             fields,
             children,
             filename,
+            conflict: None,
+            action: None,
+            notation: None,
         };
 
         self.list
@@ -311,6 +346,9 @@ impl SchemaList {
             fields,
             children,
             filename,
+            conflict: config.conflict,
+            action: config.action,
+            notation: config.notation,
         };
 
         self.list
@@ -349,6 +387,47 @@ impl SchemaList {
     pub fn get(&self, name: &str) -> Option<&Schema> {
         self.list.get(&name.to_lowercase())
     }
+
+    /// Datatype of `schema.field`, or of `field` in the first schema (by name) that has it
+    pub fn field_type(&self, field: &str) -> Option<&ConfigDatatype> {
+        if let Some((schema, field)) = field.split_once('.') {
+            return Some(&self.get(schema)?.fields.get(field)?.format);
+        }
+        let mut names = self.list.keys().collect::<Vec<_>>();
+        names.sort();
+        names
+            .into_iter()
+            .find_map(|name| Some(&self.list.get(name)?.fields.get(field)?.format))
+    }
+
+    /// Schema names from one of `from` down to `name` through children.
+    /// None if `name` can't be reached
+    pub fn chain_to(&self, from: &[&str], name: &str) -> Option<Vec<String>> {
+        let mut stack = from
+            .iter()
+            .rev()
+            .map(|f| vec![f.to_string()])
+            .collect::<Vec<_>>();
+        let mut seen = vec![];
+        while let Some(chain) = stack.pop() {
+            let Some(schema) = self.get(chain.last()?) else {
+                continue;
+            };
+            if schema.name.eq_ignore_ascii_case(name) {
+                return Some(chain);
+            }
+            if seen.contains(&schema.name) {
+                continue;
+            }
+            seen.push(schema.name.clone());
+            for child in schema.children.iter().rev() {
+                let mut next = chain.clone();
+                next.push(child.clone());
+                stack.push(next);
+            }
+        }
+        None
+    }
 }
 
 impl From<&SchemaConfig> for SchemaList {
@@ -400,12 +479,12 @@ fn fit_test() {
     let data_test2 = vec![S!(a, s), S!(b, 2), S!(c, a)];
     let data_test2_no_c = vec![S!(a, s), S!(b, 2)];
 
-    assert_eq!(true, test1.is_fit(&data_test1));
-    assert_eq!(false, test2.is_fit(&data_test1));
-    assert_eq!(false, test1.is_fit(&data_test2));
-    assert_eq!(true, test2.is_fit(&data_test2));
-    assert_eq!(false, test1.is_fit(&data_test2_no_c));
-    assert_eq!(false, test2.is_fit(&data_test2_no_c));
+    assert!(test1.fit_error(&data_test1).is_none());
+    assert!(test2.fit_error(&data_test1).is_some());
+    assert!(test1.fit_error(&data_test2).is_some());
+    assert!(test2.fit_error(&data_test2).is_none());
+    assert!(test1.fit_error(&data_test2_no_c).is_some());
+    assert!(test2.fit_error(&data_test2_no_c).is_some());
 }
 
 #[test]
@@ -417,6 +496,19 @@ fn generate_string_test() {
     let data = vec![S!(a, 2), S!(b, test)];
     dbg!(test.generate_string(&data));
 
+    // tes.a is the start of the name, unless strict
+    let mut test = test.clone();
+    let data = vec![S!(a, 2), S!(tes.a, 3)];
+    assert_eq!(test.generate_string(&data), "3.txt");
+    test.notation = Some(Notation::Strict);
+    assert_eq!(test.generate_string(&data), "2.txt");
+    // a typo only with fuzzy
+    let data = vec![S!(a, 2), S!(tset1.a, 3)];
+    test.notation = None;
+    assert_eq!(test.generate_string(&data), "2.txt");
+    test.notation = Some(Notation::Fuzzy);
+    assert_eq!(test.generate_string(&data), "3.txt");
+
     // assert_eq!(test1.generate_string(&data_test1), "2 test");
     // assert_eq!(test2.generate_string(&data_test2), "s 2 a");
     // assert_eq!(test2.generate_string(&data_test2_no_c), "s 2");
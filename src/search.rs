@@ -1,10 +1,447 @@
 // Grammar
 // field: a b | c => field(a and (b or c))
-// field: a field2: d =>
+// field: a field2: d => field(a) and field2(d)
+// a value without field is a full text search on the name, the path and
+// every field, `naru` also find `Naruto_[01].mkv`. Unless exact, it also
+// match with typos, `naurto` find it too (see fuzzy.rs), as long as the
+// first letter is right
+// -a or !a => not a, (a b) | c => group
+// "a b" => one value with space
+// field: >a >=a <a <=a a..b a.. ..b => range, only after a field
+//
+// a query is turned into a WHERE condition on the files table,
+// fields are looked up in the any table.
+// ranges compare numbers for num/flo fields in schema, dates for modified,
+// and text for the rest. modified also take an age: modified: <7d is
+// changed in the last 7 days (h, d, w, m for 30 days, y for 365 days)
 
-enum Operation {
+use std::collections::HashMap;
+
+use chrono::{Duration, NaiveDate, NaiveDateTime, Utc};
+
+use crate::{
+    config_reader::ConfigDatatype, error::FOError, parser::search::full_query, schema::SchemaList,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operation {
     Field(String, Vec<Operation>),
     Value(String),
     And(Vec<Operation>),
     Or(Vec<Operation>),
+    Not(Box<Operation>),
+    /// min, max
+    Range(Option<Bound>, Option<Bound>),
+}
+
+/// One end of a range
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bound {
+    pub value: String,
+    pub inclusive: bool,
+}
+
+/// how a field is compared in a range
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Number,
+    Date,
+    Text,
+}
+
+/// fields that are columns of the files table, (field, column)
+const FILE_COLUMNS: [(&str, &str); 3] =
+    [("name", "name"), ("path", "path"), ("modified", "last_mod")];
+
+/// MATCH for files_fts with the words close to each value without field
+pub type FuzzyMatches = HashMap<String, String>;
+
+/// same format as files.last_mod
+const DATE_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+impl Operation {
+    pub fn parse(query: &str) -> Result<Operation, FOError> {
+        match full_query(query) {
+            Ok((_, operation)) => Ok(operation),
+            Err(e) => Err(FOError::QueryError(e.to_string())),
+        }
+    }
+
+    /// WHERE condition for the files table, with the parameters to bind in order.
+    /// The schema list tell how the fields are compared, fuzzy add files to the values
+    pub fn to_sql(
+        &self,
+        schemalist: &SchemaList,
+        fuzzy: &FuzzyMatches,
+    ) -> Result<(String, Vec<String>), FOError> {
+        let mut params = vec![];
+        let sql = self.condition(None, schemalist, fuzzy, &mut params)?;
+        Ok((sql, params))
+    }
+
+    /// values without field, negated ones too
+    pub fn values(&self) -> Vec<&str> {
+        let mut values = vec![];
+        self.collect_values(true, &mut values);
+        values
+    }
+
+    /// values without field that are not negated, what the results are ranked with
+    pub fn rank_values(&self) -> Vec<&str> {
+        let mut values = vec![];
+        self.collect_values(false, &mut values);
+        values
+    }
+
+    /// MATCH for files_fts to rank the results with, from the values without field
+    pub fn rank_query(&self) -> Option<String> {
+        let values = self
+            .rank_values()
+            .iter()
+            .map(|value| fts_query(value))
+            .filter(|query| !query.is_empty())
+            .collect::<Vec<_>>();
+        match values.is_empty() {
+            true => None,
+            false => Some(values.join(" OR ")),
+        }
+    }
+
+    fn collect_values<'a>(&'a self, negated: bool, values: &mut Vec<&'a str>) {
+        match self {
+            Operation::Value(value) => values.push(value),
+            Operation::And(list) | Operation::Or(list) => list
+                .iter()
+                .for_each(|operation| operation.collect_values(negated, values)),
+            Operation::Not(operation) if negated => operation.collect_values(negated, values),
+            _ => (),
+        }
+    }
+
+    fn condition(
+        &self,
+        field: Option<&str>,
+        schemalist: &SchemaList,
+        fuzzy: &FuzzyMatches,
+        params: &mut Vec<String>,
+    ) -> Result<String, FOError> {
+        Ok(match self {
+            Operation::Field(name, values) if values.is_empty() => {
+                params.push(name.to_owned());
+                "EXISTS (SELECT 1 FROM any WHERE any.file_id = files.id AND any.field = ? COLLATE NOCASE)"
+                    .to_owned()
+            }
+            Operation::Field(name, values) => {
+                join(values, " AND ", Some(name), schemalist, fuzzy, params)?
+            }
+            Operation::Value(value) => value_condition(field, value, fuzzy, params),
+            Operation::And(list) => join(list, " AND ", field, schemalist, fuzzy, params)?,
+            Operation::Or(list) => join(list, " OR ", field, schemalist, fuzzy, params)?,
+            Operation::Not(operation) => format!(
+                "NOT {}",
+                operation.condition(field, schemalist, fuzzy, params)?
+            ),
+            Operation::Range(min, max) => {
+                let field = field.ok_or_else(|| {
+                    FOError::QueryError("a range need a field, like epinum: 1..3".to_owned())
+                })?;
+                range_condition(field, min, max, schemalist, params)?
+            }
+        })
+    }
+}
+
+fn join(
+    list: &[Operation],
+    separator: &str,
+    field: Option<&str>,
+    schemalist: &SchemaList,
+    fuzzy: &FuzzyMatches,
+    params: &mut Vec<String>,
+) -> Result<String, FOError> {
+    let conditions = list
+        .iter()
+        .map(|operation| operation.condition(field, schemalist, fuzzy, params))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(format!("({})", conditions.join(separator)))
+}
+
+fn file_column(field: &str) -> Option<&'static str> {
+    FILE_COLUMNS
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(field))
+        .map(|(_, column)| *column)
+}
+
+fn value_condition(
+    field: Option<&str>,
+    value: &str,
+    fuzzy: &FuzzyMatches,
+    params: &mut Vec<String>,
+) -> String {
+    let pattern = like(value);
+    match field {
+        None if !fts_query(value).is_empty() => {
+            params.push(match fuzzy.get(value) {
+                Some(close) => format!("({}) OR {}", fts_query(value), close),
+                None => fts_query(value),
+            });
+            "files.id IN (SELECT rowid FROM files_fts WHERE files_fts MATCH ?)".to_owned()
+        }
+        None => {
+            params.extend([pattern.clone(), pattern]);
+            "(files.name LIKE ? ESCAPE '\\' OR EXISTS (SELECT 1 FROM any \
+            WHERE any.file_id = files.id AND any.field_value LIKE ? ESCAPE '\\'))"
+                .to_owned()
+        }
+        Some(field) => {
+            params.extend([field.to_owned(), pattern.clone()]);
+            let mut sql = "(EXISTS (SELECT 1 FROM any WHERE any.file_id = files.id \
+                AND any.field = ? COLLATE NOCASE AND any.field_value LIKE ? ESCAPE '\\')"
+                .to_owned();
+            if let Some(column) = file_column(field) {
+                params.push(pattern);
+                sql.push_str(&format!(" OR files.{} LIKE ? ESCAPE '\\'", column));
+            }
+            sql.push(')');
+            sql
+        }
+    }
+}
+
+fn kind(field: &str, schemalist: &SchemaList) -> Kind {
+    if field.eq_ignore_ascii_case("modified") {
+        return Kind::Date;
+    }
+    match schemalist.field_type(field) {
+        Some(ConfigDatatype::Integer(_) | ConfigDatatype::Float(_)) => Kind::Number,
+        _ => Kind::Text,
+    }
+}
+
+fn range_condition(
+    field: &str,
+    min: &Option<Bound>,
+    max: &Option<Bound>,
+    schemalist: &SchemaList,
+    params: &mut Vec<String>,
+) -> Result<String, FOError> {
+    let kind = kind(field, schemalist);
+    let mut lower = min.iter().cloned().collect::<Vec<_>>();
+    let mut upper = max.iter().cloned().collect::<Vec<_>>();
+    match kind {
+        Kind::Number => {
+            if let Some(bound) = lower
+                .iter()
+                .chain(&upper)
+                .find(|bound| bound.value.parse::<f64>().is_err())
+            {
+                return Err(FOError::QueryError(format!(
+                    "{} is not a number",
+                    bound.value
+                )));
+            }
+        }
+        Kind::Date => {
+            let (mut dates_lower, mut dates_upper) = (vec![], vec![]);
+            for bound in lower {
+                // an age is the other way around, >7d is before 7 days ago
+                match date_bound(&bound, false)? {
+                    (date, true) => dates_upper.push(date),
+                    (date, false) => dates_lower.push(date),
+                }
+            }
+            for bound in upper {
+                match date_bound(&bound, true)? {
+                    (date, true) => dates_lower.push(date),
+                    (date, false) => dates_upper.push(date),
+                }
+            }
+            (lower, upper) = (dates_lower, dates_upper);
+        }
+        Kind::Text => (),
+    }
+
+    // any.field_value is text, numbers need a cast
+    let (value, param, check) = match kind {
+        Kind::Number => (
+            "CAST(any.field_value AS REAL)",
+            "CAST(? AS REAL)",
+            " AND any.field_value GLOB '*[0-9]*' AND any.field_value NOT GLOB '*[^0-9.+-]*'",
+        ),
+        _ => ("any.field_value", "?", ""),
+    };
+    let compare = |value: &str, params: &mut Vec<String>| {
+        let mut sql = vec![];
+        for bound in &lower {
+            params.push(bound.value.to_owned());
+            let op = if bound.inclusive { ">=" } else { ">" };
+            sql.push(format!("{} {} {}", value, op, param));
+        }
+        for bound in &upper {
+            params.push(bound.value.to_owned());
+            let op = if bound.inclusive { "<=" } else { "<" };
+            sql.push(format!("{} {} {}", value, op, param));
+        }
+        sql.join(" AND ")
+    };
+
+    params.push(field.to_owned());
+    let mut sql = format!(
+        "(EXISTS (SELECT 1 FROM any WHERE any.file_id = files.id \
+        AND any.field = ? COLLATE NOCASE{} AND {})",
+        check,
+        compare(value, params)
+    );
+    if let Some(column) = file_column(field) {
+        sql.push_str(&format!(
+            " OR ({})",
+            compare(&format!("files.{}", column), params)
+        ));
+    }
+    sql.push(')');
+    Ok(sql)
+}
+
+/// the bound as a date in DATE_FORMAT, and if it was an age (7d, 2w, ...)
+fn date_bound(bound: &Bound, is_max: bool) -> Result<(Bound, bool), FOError> {
+    let (date, is_age) = match age(&bound.value) {
+        Some(age) => ((Utc::now() - age).naive_utc(), true),
+        None => match parse_date(&bound.value, is_max) {
+            Some(date) => (date, false),
+            None => {
+                return Err(FOError::QueryError(format!(
+                    "{} is not a date or an age like 7d",
+                    bound.value
+                )))
+            }
+        },
+    };
+    Ok((
+        Bound {
+            value: date.format(DATE_FORMAT).to_string(),
+            inclusive: bound.inclusive,
+        },
+        is_age,
+    ))
+}
+
+/// 3h, 7d, 2w, 1m, 1y
+fn age(value: &str) -> Option<Duration> {
+    let unit = value.chars().last()?;
+    let amount = value[..value.len() - unit.len_utf8()].parse::<i64>().ok()?;
+    match unit {
+        'h' => Some(Duration::hours(amount)),
+        'd' => Some(Duration::days(amount)),
+        'w' => Some(Duration::weeks(amount)),
+        'm' => Some(Duration::days(amount * 30)),
+        'y' => Some(Duration::days(amount * 365)),
+        _ => None,
+    }
+}
+
+/// 2023-01-31, 2023-01-31T10:00 or 2023-01-31T10:00:00.
+/// a max without time is the end of that day
+fn parse_date(value: &str, is_max: bool) -> Option<NaiveDateTime> {
+    for format in ["%Y-%m-%dT%H:%M:%S", "%Y-%m-%dT%H:%M"] {
+        if let Ok(date) = NaiveDateTime::parse_from_str(value, format) {
+            return Some(date);
+        }
+    }
+    let day = NaiveDate::parse_from_str(value, "%Y-%m-%d").ok()?;
+    match is_max {
+        true => day.and_hms_opt(23, 59, 59),
+        false => day.and_hms_opt(0, 0, 0),
+    }
+}
+
+/// every word of the value as a prefix, split like the files_fts tokenizer
+fn fts_query(value: &str) -> String {
+    value
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|token| !token.is_empty())
+        .map(|token| format!("\"{}\"*", token))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// contains, % and _ are taken as they are
+fn like(value: &str) -> String {
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    format!("%{}%", escaped)
+}
+
+#[cfg(test)]
+fn value(v: &str) -> Operation {
+    Operation::Value(v.to_owned())
+}
+
+#[test]
+fn test_parse() {
+    use Operation::*;
+
+    assert_eq!(
+        Operation::parse("name: naruto epinum: 3 | 4").unwrap(),
+        And(vec![
+            Field("name".to_owned(), vec![value("naruto")]),
+            Field("epinum".to_owned(), vec![Or(vec![value("3"), value("4")])]),
+        ])
+    );
+    assert_eq!(
+        Operation::parse("field: a b | c").unwrap(),
+        Field(
+            "field".to_owned(),
+            vec![value("a"), Or(vec![value("b"), value("c")])]
+        )
+    );
+    assert_eq!(
+        Operation::parse(r#"-(a | "b c") tags:"#).unwrap(),
+        And(vec![
+            Not(Box::new(Or(vec![value("a"), value("b c")]))),
+            Field("tags".to_owned(), vec![]),
+        ])
+    );
+    assert_eq!(
+        Operation::parse("anime.name: !x | y").unwrap(),
+        Field(
+            "anime.name".to_owned(),
+            vec![Or(vec![Not(Box::new(value("x"))), value("y")])]
+        )
+    );
+    let bound = |value: &str, inclusive| {
+        Some(Bound {
+            value: value.to_owned(),
+            inclusive,
+        })
+    };
+    assert_eq!(
+        Operation::parse("epinum: 10..20 >=1 modified: <7d").unwrap(),
+        And(vec![
+            Field(
+                "epinum".to_owned(),
+                vec![
+                    Range(bound("10", true), bound("20", true)),
+                    Range(bound("1", true), None),
+                ]
+            ),
+            Field("modified".to_owned(), vec![Range(None, bound("7d", false))]),
+        ])
+    );
+    assert_eq!(
+        Operation::parse("v: 1.5.. | ..0.5").unwrap(),
+        Field(
+            "v".to_owned(),
+            vec![Or(vec![
+                Range(bound("1.5", true), None),
+                Range(None, bound("0.5", true)),
+            ])]
+        )
+    );
+    // only after a field
+    assert_eq!(Operation::parse(">3").unwrap(), value(">3"));
+    assert!(Operation::parse("(a").is_err());
+    assert!(Operation::parse("").is_err());
 }
@@ -0,0 +1,280 @@
+// Verify
+// the content of the files is hashed again and compared with the hash in the
+// index, a file that changed without its size or modified time changing is
+// rotting. files changed the normal way are left to the indexer.
+// the files verified the longest time ago go first, so a run stopped by
+// --max-gb (or ctrl-c) is picked up by the next one.
+
+use std::{
+    collections::BTreeMap,
+    fmt::{Display, Formatter},
+    io::ErrorKind,
+    path::Path,
+};
+
+use crate::{
+    db::IndexDB,
+    error::FOError,
+    helper::{FileHelper, HASH_CHUNK},
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verdict {
+    /// same as the hash
+    Ok,
+    /// there was no hash of any kind yet, it's the one to compare with next time
+    Hashed,
+    /// the size or modified time is not the one indexed, not checked
+    Changed,
+    /// not the same as the hash, while the size and modified time are
+    Mismatch,
+    Missing,
+    Unreadable(String),
+}
+
+impl Verdict {
+    /// something to look at
+    pub fn is_bad(&self) -> bool {
+        matches!(
+            self,
+            Verdict::Mismatch | Verdict::Missing | Verdict::Unreadable(_)
+        )
+    }
+}
+
+impl Display for Verdict {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Verdict::Ok => write!(f, "ok"),
+            Verdict::Hashed => write!(f, "hashed"),
+            Verdict::Changed => write!(f, "changed since indexed"),
+            Verdict::Mismatch => write!(f, "content changed"),
+            Verdict::Missing => write!(f, "missing"),
+            Verdict::Unreadable(e) => write!(f, "unreadable ({})", e),
+        }
+    }
+}
+
+/// Verify the files, oldest verified first, until max_bytes are read.
+/// (files.path, verdict) of each file
+pub async fn verify(
+    db: &IndexDB,
+    max_bytes: Option<u64>,
+) -> Result<Vec<(String, Verdict)>, FOError> {
+    let mut read = 0;
+    let mut result = vec![];
+    for (identity, last_mod) in db.verify_queue().await? {
+        if max_bytes.is_some_and(|max_bytes| read >= max_bytes) {
+            break;
+        }
+        let file_helper = FileHelper::new(db.absolute(&identity.path));
+        let state = match file_helper.state() {
+            Ok(state) => state,
+            Err(FOError::IOError(e)) if e.kind() == ErrorKind::NotFound => {
+                result.push((identity.path, Verdict::Missing));
+                continue;
+            }
+            Err(e) => {
+                result.push((identity.path, Verdict::Unreadable(e.to_string())));
+                continue;
+            }
+        };
+        let modified = file_helper.last_mod().unwrap_or(state.modified);
+        if identity.size.is_some_and(|size| size != state.size)
+            || modified.timestamp() != last_mod.timestamp()
+        {
+            result.push((identity.path, Verdict::Changed));
+            continue;
+        }
+
+        read += state.size as u64;
+        let md5 = match file_helper.md5() {
+            Ok(md5) => md5,
+            Err(e) => {
+                result.push((identity.path, Verdict::Unreadable(e.to_string())));
+                continue;
+            }
+        };
+        // no full hash yet, the partial one from the indexer is the whole
+        // file when it's small, the head and tail otherwise
+        let same = match (&identity.hash, &identity.partial_hash) {
+            (Some(hash), _) => Some(md5.eq(hash)),
+            (None, Some(partial)) if state.size as u64 <= 2 * HASH_CHUNK => Some(md5.eq(partial)),
+            (None, Some(partial)) => match file_helper.partial_md5() {
+                Ok(head_tail) => Some(head_tail.eq(partial)),
+                Err(e) => {
+                    result.push((identity.path, Verdict::Unreadable(e.to_string())));
+                    continue;
+                }
+            },
+            (None, None) => None,
+        };
+        let verdict = match same {
+            None => {
+                // indexed before the sizes were kept
+                if identity.size.is_none() {
+                    db.content_hash(identity.id, false).await?;
+                }
+                db.set_verified(identity.id, Some(&md5)).await?;
+                Verdict::Hashed
+            }
+            Some(true) => {
+                db.set_verified(identity.id, Some(&md5)).await?;
+                Verdict::Ok
+            }
+            // not marked as verified, it stays first in the queue
+            Some(false) => Verdict::Mismatch,
+        };
+        result.push((identity.path, verdict));
+    }
+    Ok(result)
+}
+
+/// The verify command on the tree at root. fo.db is checked as it is, indexing
+/// first would delete the missing files and take the changed ones as they are now
+pub async fn verify_root<P: AsRef<Path>>(
+    root: P,
+    max_gb: Option<f64>,
+) -> Result<Vec<(String, Verdict)>, FOError> {
+    let db = IndexDB::open(root).await?;
+    let max_bytes = max_gb.map(|gb| (gb * 1024.0 * 1024.0 * 1024.0) as u64);
+    verify(&db, max_bytes).await
+}
+
+/// "3 files verified: 1 missing, 2 ok"
+pub fn summary(result: &[(String, Verdict)]) -> String {
+    let mut counts = BTreeMap::new();
+    for (_, verdict) in result {
+        let name = match verdict {
+            Verdict::Unreadable(_) => "unreadable".to_owned(),
+            verdict => verdict.to_string(),
+        };
+        *counts.entry(name).or_insert(0) += 1;
+    }
+    let counts = counts
+        .iter()
+        .map(|(name, count)| format!("{} {}", count, name))
+        .collect::<Vec<_>>();
+    format!("{} files verified: {}", result.len(), counts.join(", "))
+}
+
+#[async_std::test]
+async fn test_verify() {
+    use std::fs;
+
+    let root = crate::helper::test_util::TestRoot::new("verify");
+    for name in ["a.mkv", "b.mkv", "c.mkv"] {
+        fs::write(root.join(name), name).unwrap();
+    }
+    let db = root.db().await;
+    for name in ["./a.mkv", "./b.mkv", "./c.mkv"] {
+        db.add_file(name, 0).await.unwrap();
+    }
+    let verdicts = |result: Vec<(String, Verdict)>| {
+        result
+            .into_iter()
+            .map(|(_, verdict)| verdict)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        verdicts(verify(&db, None).await.unwrap()),
+        [Verdict::Hashed, Verdict::Hashed, Verdict::Hashed]
+    );
+
+    // rot: the content changed, the size and modified time didn't
+    let a = fs::File::options()
+        .write(true)
+        .open(root.join("a.mkv"))
+        .unwrap();
+    let modified = a.metadata().unwrap().modified().unwrap();
+    fs::write(root.join("a.mkv"), "x.mkv").unwrap();
+    a.set_modified(modified).unwrap();
+    fs::remove_file(root.join("c.mkv")).unwrap();
+    assert_eq!(
+        verify(&db, None).await.unwrap(),
+        [
+            ("./a.mkv".to_owned(), Verdict::Mismatch),
+            ("./b.mkv".to_owned(), Verdict::Ok),
+            ("./c.mkv".to_owned(), Verdict::Missing),
+        ]
+    );
+
+    // the bad ones stay first, the limit stop it after the first one read
+    assert_eq!(
+        verify(&db, Some(1)).await.unwrap(),
+        [("./a.mkv".to_owned(), Verdict::Mismatch)]
+    );
+    fs::write(root.join("b.mkv"), "changed").unwrap();
+    let result = verify(&db, None).await.unwrap();
+    assert_eq!(result[2], ("./b.mkv".to_owned(), Verdict::Changed));
+}
+
+#[async_std::test]
+async fn test_verify_root() {
+    use std::{fs, time::Duration};
+
+    let root = crate::helper::test_util::TestRoot::new("verify-root");
+    for name in ["a.mkv", "b.mkv", "c.mkv"] {
+        fs::write(root.join(name), name).unwrap();
+    }
+    let mut db = root.db().await;
+    crate::indexer::Indexer::open(&mut db)
+        .indexing("./", 0)
+        .await
+        .unwrap();
+    let result = verify_root(&root, None).await.unwrap();
+    assert_eq!(summary(&result), "3 files verified: 3 ok");
+
+    // the indexer isn't run again, so they are still in fo.db to be reported
+    fs::remove_file(root.join("a.mkv")).unwrap();
+    let b = fs::File::options()
+        .write(true)
+        .open(root.join("b.mkv"))
+        .unwrap();
+    b.set_modified(std::time::SystemTime::now() + Duration::from_secs(5))
+        .unwrap();
+    let result = verify_root(&root, None).await.unwrap();
+    assert!(result.contains(&("./a.mkv".to_owned(), Verdict::Missing)));
+    assert!(result.contains(&("./b.mkv".to_owned(), Verdict::Changed)));
+    assert_eq!(
+        summary(&result),
+        "3 files verified: 1 changed since indexed, 1 missing, 1 ok"
+    );
+}
+
+#[async_std::test]
+async fn test_first_verify() {
+    use std::fs;
+
+    let root = crate::helper::test_util::TestRoot::new("first-verify");
+    let big = vec![1; 3 * HASH_CHUNK as usize];
+    fs::write(root.join("small.mkv"), "small").unwrap();
+    fs::write(root.join("big.mkv"), &big).unwrap();
+    let mut db = root.db().await;
+    crate::indexer::Indexer::open(&mut db)
+        .indexing("./", 0)
+        .await
+        .unwrap();
+
+    // rotted before the first verify, the partial hash of the indexer tells
+    let mut rotten = big.clone();
+    rotten[0] = 2;
+    for (name, content) in [("small.mkv", b"smell".to_vec()), ("big.mkv", rotten)] {
+        let file = fs::File::options()
+            .write(true)
+            .open(root.join(name))
+            .unwrap();
+        let modified = file.metadata().unwrap().modified().unwrap();
+        fs::write(root.join(name), content).unwrap();
+        file.set_modified(modified).unwrap();
+    }
+    let mut result = verify(&db, None).await.unwrap();
+    result.sort_by(|a, b| a.0.cmp(&b.0));
+    assert_eq!(
+        result,
+        [
+            ("./big.mkv".to_owned(), Verdict::Mismatch),
+            ("./small.mkv".to_owned(), Verdict::Mismatch),
+        ]
+    );
+}
@@ -0,0 +1,235 @@
+// Views
+// a view is a saved search in `_views`, `views sync` make a folder of
+// symlinks for each one in _views/<name>, so a file manager can browse it.
+// ``` yaml
+// _views:
+//     unwatched anime: "schema: anime -watched:"
+//     math books: "tags: math ext: pdf"
+// ```
+// links that are not in the results anymore are removed, other files in
+// the folder are left alone. the links are relative to survive a move of
+// the whole library. only files are linked, not folders.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    config_reader::Config,
+    db::IndexDB,
+    error::FOError,
+    helper::relative_to,
+    organizer::{free_name, symlink},
+    schema::SchemaList,
+    search::Operation,
+};
+
+/// in the root, skipped by the indexer and organize
+pub const VIEWS_FOLDER: &str = "_views";
+
+/// What sync did to one view
+#[derive(Debug, Default, PartialEq)]
+pub struct ViewSync {
+    pub name: String,
+    pub linked: usize,
+    pub removed: usize,
+    pub kept: usize,
+}
+
+/// Make or refresh the folder of every view, or only the named ones.
+/// All views: the folders of views not in config anymore lose their links too
+pub async fn sync<T: AsRef<str>>(
+    db: &IndexDB,
+    config: &Config,
+    schemalist: &SchemaList,
+    only: &[T],
+) -> Result<Vec<ViewSync>, FOError> {
+    let views_folder = db.absolute(VIEWS_FOLDER);
+    let mut views = config.views.iter().collect::<Vec<_>>();
+    views.sort();
+    if let Some(name) = only
+        .iter()
+        .find(|name| !config.views.contains_key(name.as_ref()))
+    {
+        return Err(FOError::QueryError(format!(
+            "no view named {}",
+            name.as_ref()
+        )));
+    }
+
+    let mut synced = vec![];
+    for (name, search) in views {
+        if !only.is_empty() && !only.iter().any(|only| only.as_ref() == name) {
+            continue;
+        }
+        let folder = view_folder(&views_folder, name)?;
+        let operation = Operation::parse(search)
+            .map_err(|e| FOError::QueryError(format!("view {}: {}", name, e)))?;
+        // exact, a saved search should not change with the typos
+        let targets = db
+            .search(&operation, schemalist, true)
+            .await?
+            .into_iter()
+            .filter(|hit| !hit.is_folder)
+            .map(|hit| (hit.name.clone(), db.absolute(&hit.path)))
+            .collect::<Vec<_>>();
+        let mut sync = link_all(&folder, targets)?;
+        sync.name = name.to_owned();
+        synced.push(sync);
+    }
+
+    if only.is_empty() && views_folder.is_dir() {
+        for entry in fs::read_dir(&views_folder)? {
+            let folder = entry?.path();
+            let name = folder.file_name().unwrap().to_string_lossy().to_string();
+            if config.views.contains_key(&name) || !folder.is_dir() {
+                continue;
+            }
+            let mut sync = link_all(&folder, vec![])?;
+            // keep it if something else is in there
+            let _ = fs::remove_dir(&folder);
+            sync.name = name;
+            synced.push(sync);
+        }
+    }
+    Ok(synced)
+}
+
+/// a view name is one folder, not a path
+fn view_folder(views_folder: &Path, name: &str) -> Result<PathBuf, FOError> {
+    let mut components = Path::new(name).components();
+    match (components.next(), components.next()) {
+        (Some(std::path::Component::Normal(_)), None) => Ok(views_folder.join(name)),
+        _ => Err(FOError::QueryError(format!(
+            "view name {:?} should be a folder name",
+            name
+        ))),
+    }
+}
+
+/// the folder will have a link to each target (name, path) and no other link
+fn link_all(folder: &Path, targets: Vec<(String, PathBuf)>) -> Result<ViewSync, FOError> {
+    fs::create_dir_all(folder)?;
+    let mut sync = ViewSync::default();
+
+    // links already there, name: target. taken is path: what is there
+    let mut existing = BTreeMap::new();
+    let mut taken = HashMap::new();
+    for entry in fs::read_dir(folder)? {
+        let path = entry?.path();
+        let name = path.file_name().unwrap().to_string_lossy().to_string();
+        match fs::read_link(&path) {
+            Ok(target) => {
+                existing.insert(name, folder.join(target));
+            }
+            Err(_) => {
+                taken.insert(path.clone(), path);
+            }
+        }
+    }
+
+    let mut wanted = vec![];
+    for (name, target) in targets {
+        match existing
+            .iter()
+            .find(|(_, linked)| same_path(linked, &target))
+        {
+            Some((name, _)) => {
+                let name = name.to_owned();
+                existing.remove(&name);
+                taken.insert(folder.join(name), target);
+                sync.kept += 1;
+            }
+            None => wanted.push((name, target)),
+        }
+    }
+    for name in existing.into_keys() {
+        fs::remove_file(folder.join(name))?;
+        sync.removed += 1;
+    }
+    for (name, target) in wanted {
+        let mut path = folder.join(name);
+        if taken.contains_key(&path) {
+            path = free_name(&path, &taken);
+        }
+        symlink(relative_to(&target, folder), &path)?;
+        taken.insert(path, target);
+        sync.linked += 1;
+    }
+    Ok(sync)
+}
+
+/// a/b/../c and a/c are the same
+fn same_path(a: &Path, b: &Path) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+#[async_std::test]
+async fn test_sync() {
+    use crate::{helper::FileHelper, indexer::Indexer};
+
+    let root = crate::helper::test_util::TestRoot::new("views");
+    fs::create_dir_all(root.join("a")).unwrap();
+    for name in ["a/naruto 01.mkv", "naruto 01.mkv", "bleach 01.mkv"] {
+        fs::write(root.join(name), name).unwrap();
+    }
+    fs::write(
+        root.join("_data.yaml"),
+        r#"
+        _views:
+            naruto: naruto
+            mkv: "name: mkv"
+        "#,
+    )
+    .unwrap();
+    let config = FileHelper::new(&root).read_config().unwrap();
+    let sl = SchemaList::from(&config.schema);
+    let mut db = root.db().await;
+    Indexer::open(&mut db).indexing("./", 0).await.unwrap();
+
+    let only: [&str; 0] = [];
+    let synced = sync(&db, &config, &sl, &only).await.unwrap();
+    assert_eq!(synced[1].name, "naruto");
+    assert_eq!(synced[1].linked, 2);
+    let naruto = root.join(VIEWS_FOLDER).join("naruto");
+    let links = || {
+        let mut links = fs::read_dir(&naruto)
+            .unwrap()
+            .map(|entry| {
+                let link = entry.unwrap().path();
+                let name = link.file_name().unwrap().to_string_lossy().to_string();
+                (name, fs::read_to_string(link).unwrap())
+            })
+            .collect::<Vec<_>>();
+        links.sort();
+        links
+    };
+    let mut contents = links().into_iter().map(|(_, c)| c).collect::<Vec<_>>();
+    contents.sort();
+    assert_eq!(contents, ["a/naruto 01.mkv", "naruto 01.mkv"]);
+    assert_eq!(links()[1].0, "naruto 01.mkv");
+
+    // the links are not indexed, and the ones not found anymore are removed
+    Indexer::open(&mut db).indexing("./", 0).await.unwrap();
+    let mut config = config;
+    config
+        .views
+        .insert("naruto".to_owned(), "naruto -path: a/".to_owned());
+    let synced = sync(&db, &config, &sl, &["naruto"]).await.unwrap();
+    assert_eq!(
+        synced,
+        [ViewSync {
+            name: "naruto".to_owned(),
+            linked: 0,
+            removed: 1,
+            kept: 1,
+        }]
+    );
+    assert_eq!(links().len(), 1);
+    assert!(sync(&db, &config, &sl, &["other"]).await.is_err());
+}
@@ -0,0 +1,159 @@
+// Watch mode
+// inotify tell us when a file is written or moved into the root,
+// each batch of events is organized as one journal run.
+// when a yaml in the root change, the config is read again.
+// unfinished files are skipped by the organizer, they come back with
+// the next event (renamed from .part or written again).
+
+use std::{
+    collections::BTreeSet,
+    ffi::{OsStr, OsString},
+    path::{Path, PathBuf},
+};
+
+use async_std::task;
+
+use inotify::{EventMask, Inotify, WatchMask};
+
+use crate::{
+    config_reader::{Action, ConflictPolicy},
+    db::IndexDB,
+    error::FOError,
+    helper::FileHelper,
+    journal::Journal,
+    organizer::{self, Organizer},
+    schema::SchemaList,
+};
+
+pub async fn watch<P: AsRef<Path>>(
+    root: P,
+    conflict: Option<ConflictPolicy>,
+    action: Option<Action>,
+) -> Result<(), FOError> {
+    let root = root.as_ref().to_owned();
+    let inotify = start(&root)?;
+    println!("watching {:?}", root);
+    run(root, inotify, conflict, action).await
+}
+
+fn start(root: &Path) -> Result<Inotify, FOError> {
+    let inotify = Inotify::init()?;
+    inotify.watches().add(
+        root,
+        WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO | WatchMask::MOVED_FROM | WatchMask::DELETE,
+    )?;
+    Ok(inotify)
+}
+
+/// (mask, name) of the next events, waited for on a thread of its own
+async fn next_events(
+    mut inotify: Inotify,
+) -> (Inotify, Result<Vec<(EventMask, Option<OsString>)>, FOError>) {
+    task::spawn_blocking(move || {
+        let mut buffer = [0; 4096];
+        let events = inotify
+            .read_events_blocking(&mut buffer)
+            .map(|events| {
+                events
+                    .map(|event| (event.mask, event.name.map(OsStr::to_owned)))
+                    .collect()
+            })
+            .map_err(FOError::from);
+        (inotify, events)
+    })
+    .await
+}
+
+async fn run(
+    root: PathBuf,
+    mut inotify: Inotify,
+    conflict: Option<ConflictPolicy>,
+    action: Option<Action>,
+) -> Result<(), FOError> {
+    let root = root.as_path();
+    let mut config = FileHelper::new(root).read_config()?;
+    let db = IndexDB::open(root).await?;
+
+    loop {
+        let mut files = BTreeSet::new();
+        let mut reload = false;
+        let events;
+        (inotify, events) = next_events(inotify).await;
+        for (mask, name) in events? {
+            let Some(name) = name else { continue };
+            if mask.contains(EventMask::ISDIR) {
+                continue;
+            }
+            let file = FileHelper::new(root.join(name));
+            if file.is_config() {
+                reload |= !file.file_name().starts_with("fo.db");
+            } else if mask.intersects(EventMask::CLOSE_WRITE | EventMask::MOVED_TO) {
+                files.insert(file.get_path().to_owned());
+            }
+        }
+
+        if reload {
+            match FileHelper::new(root).read_config() {
+                Ok(new_config) => {
+                    config = new_config;
+                    println!("config reloaded");
+                }
+                Err(e) => println!("config error, the old one is kept | error: {}", e),
+            }
+        }
+
+        // already moved away by an earlier batch
+        files.retain(|path| path.is_file());
+        if files.is_empty() {
+            continue;
+        }
+
+        let sl = SchemaList::from(&config.schema);
+        let organizer = Organizer::new(root, &config, &sl)
+            .with_conflict(conflict)
+            .with_action(action);
+        let plan = organizer
+            .plan_files(files.into_iter().map(FileHelper::new).collect())
+            .await;
+        let journal = Journal::start(&db).await?;
+        println!("run {}", journal.run_id);
+        let result = organizer.apply(&plan, &journal).await;
+        organizer::report(&plan, &result);
+    }
+}
+
+#[async_std::test]
+async fn test_watch() {
+    use std::{fs, time::Duration};
+
+    let root = crate::helper::test_util::TestRoot::new("watch");
+    fs::write(
+        root.join("_data.yaml"),
+        r#"
+        _meta:
+            settle: 0
+            children: file
+        _schema:
+            file:
+                filename: '%group%/%name%.txt'
+                fields: group, name
+        _import:
+            - "{?}-{?}.txt": group, name
+        "#,
+    )
+    .unwrap();
+    let inotify = start(&root).unwrap();
+    let watching = task::spawn(run(root.to_path_buf(), inotify, None, None));
+    fs::write(root.join("a-1.txt"), "a").unwrap();
+
+    // the other tasks keep going while it waits for events
+    for _ in 0..100 {
+        if root.join("a/1.txt").exists() {
+            break;
+        }
+        task::sleep(Duration::from_millis(50)).await;
+    }
+    assert!(root.join("a/1.txt").exists());
+    assert!(!root.join("a-1.txt").exists());
+    watching.cancel().await;
+}